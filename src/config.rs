@@ -0,0 +1,308 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+static CONFIG_FILE: &str = "yabaictl/config.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// What to do with displays beyond the two that form composite desktops.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackMode {
+    // Refuse to restore.
+    Bail,
+    // Give each extra display a single independent space.
+    #[default]
+    IgnoreExtra,
+    // Pair up the extra displays and run the two display scheme again on
+    // each pair, with its own set of spaces.
+    Mirror,
+}
+
+// Whether to stay away from the yabai commands that need the scripting
+// addition: creating, destroying and moving spaces.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LabelsOnly {
+    // Labels only when SIP is fully enabled, which rules out the scripting
+    // addition.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+// Padding and gap applied to every space on restore, in points. Anything
+// left unset is left to yabai.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct Spacing {
+    pub top_padding: Option<u32>,
+    pub bottom_padding: Option<u32>,
+    pub left_padding: Option<u32>,
+    pub right_padding: Option<u32>,
+    pub window_gap: Option<u32>,
+}
+
+impl Spacing {
+    fn or(&self, other: &Spacing) -> Spacing {
+        Spacing {
+            top_padding: self.top_padding.or(other.top_padding),
+            bottom_padding: self.bottom_padding.or(other.bottom_padding),
+            left_padding: self.left_padding.or(other.left_padding),
+            right_padding: self.right_padding.or(other.right_padding),
+            window_gap: self.window_gap.or(other.window_gap),
+        }
+    }
+
+    // The --padding argument, or None when no side is configured. yabai
+    // sets all four sides at once, so the unset ones become 0.
+    pub fn padding(&self) -> Option<String> {
+        let sides = [
+            self.top_padding,
+            self.bottom_padding,
+            self.left_padding,
+            self.right_padding,
+        ];
+        if sides.iter().all(Option::is_none) {
+            return None;
+        }
+        let sides: Vec<String> = sides
+            .iter()
+            .map(|side| side.unwrap_or(0).to_string())
+            .collect();
+        Some(format!("abs:{}", sides.join(":")))
+    }
+}
+
+// A display that sits out of the composite desktops with spaces of its own,
+// e.g. the laptop screen next to a single external monitor.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PinnedDisplay {
+    // The display uuid, or "built-in".
+    pub display: String,
+    // The labels of the spaces on the display, in order.
+    pub labels: Vec<String>,
+}
+
+// What focus-window, swap-window and warp-window do East or West of the
+// last window on a space.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeMode {
+    // Carry on into the other half of the composite desktop.
+    #[default]
+    Cross,
+    // Come back around at the other end of the same space, and never leave
+    // the monitor.
+    Wrap,
+}
+
+// Which side the display with the menu bar is on, physically.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PrimarySide {
+    Left,
+    Right,
+}
+
+// What focus-space does when the space it is asked for doesn't exist.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingSpace {
+    // Fail, pointing at restore-spaces.
+    #[default]
+    Bail,
+    // Restore, which creates the missing spaces, then focus.
+    Restore,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct EdgeModes {
+    pub focus: EdgeMode,
+    pub swap: EdgeMode,
+    pub warp: EdgeMode,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    // How many spaces make up the composite desktops of a pair of displays.
+    // Even, so they pair up. YABAICTL_NUM_SPACES overrides it.
+    pub num_spaces: u32,
+    // Spaces beyond the target count that restore keeps instead of
+    // destroying. They are still labeled and distributed.
+    pub min_extra_spaces: u32,
+    pub fallback_mode: FallbackMode,
+    // Warn once when a single yabai command has been retried this many
+    // times.
+    pub retry_warn_threshold: u32,
+    // Warn when a single yabai command takes longer than this many
+    // milliseconds. 0 never warns.
+    pub slow_call_ms: u64,
+    // Abort any invocation still running after this many seconds, so a hung
+    // yabai can't pile up yabaictl processes. 0 disables the watchdog.
+    pub max_runtime_secs: u64,
+    pub labels_only: LabelsOnly,
+    pub spacing: Spacing,
+    // Overrides of spacing by the number of displays, e.g. no padding on
+    // the laptop screen alone. TOML keys are strings, hence not u32.
+    pub spacing_by_displays: BTreeMap<String, Spacing>,
+    pub pinned_display: Option<PinnedDisplay>,
+    // Which of the first two displays holds the even labels, the right half
+    // of the composite desktops: "menu_bar", which is yabai's display 1,
+    // "rightmost", "leftmost" or a display uuid. Ignored with a pinned
+    // display. Unset, primary_side decides, or else the physical layout.
+    pub primary_display: Option<String>,
+    // Where the display with the menu bar is. "right" keeps the even labels
+    // on it, "left" moves them to the other display, frames or not. Unset
+    // goes by the displays' frames. primary_display takes precedence.
+    pub primary_side: Option<PrimarySide>,
+    // How many times restore sends windows home. The second pass works
+    // around moves yabai drops right after a display is added. 1 saves
+    // the extra churn where that doesn't happen.
+    pub reorganize_passes: u32,
+    // The display the reserved space goes on instead of the one with the
+    // menu bar: a display uuid, "built-in" or "external". Ignored in
+    // labels-only mode.
+    pub reserved_display: Option<String>,
+    // The label of the space that windows found on the reserved space are
+    // moved to.
+    pub reserved_destination: String,
+    // Move the mouse to the center of the window focus-window, swap-window
+    // and warp-window end up on. Meant for focus follows mouse, where the
+    // pointer left behind would steal focus back.
+    pub center_mouse: bool,
+    // Keep the mouse from visiting the other monitor on the way when
+    // focusing a composite desktop. Only does anything with yabai's
+    // mouse_follows_focus on.
+    pub hold_mouse: bool,
+    pub edge_modes: EdgeModes,
+    // The layout restore gives every space, e.g. "bsp" or "stack". Unset
+    // means yabai's own default layout.
+    pub layout: Option<String>,
+    // Whether restore sets the layout of the spaces at all. Leaving it to
+    // yabai rules also spares restore from cycling through every space.
+    pub apply_layout: bool,
+    pub missing_space: MissingSpace,
+    // The yabai socket, for when it isn't where yabai puts it by default.
+    // YABAI_SOCKET takes precedence.
+    pub socket: Option<String>,
+    // The space each app belongs on, by label, e.g. Slack = "s3". See
+    // generate-rules and reorganize --live.
+    pub placements: BTreeMap<String, String>,
+}
+
+impl Config {
+    // The checks that don't depend on the displays. How many displays the
+    // spaces have to cover is up to restore, see validate_space_count().
+    pub fn validate(&self) -> Result<()> {
+        if self.num_spaces == 0 {
+            bail!("num_spaces must be at least 2");
+        }
+        // The composite desktops take one space from each side.
+        if !self.num_spaces.is_multiple_of(2) {
+            bail!("num_spaces must be even, got {}", self.num_spaces);
+        }
+        Ok(())
+    }
+
+    pub fn spacing_for(&self, num_displays: u32) -> Spacing {
+        match self.spacing_by_displays.get(&num_displays.to_string()) {
+            Some(spacing) => spacing.or(&self.spacing),
+            None => self.spacing.clone(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            num_spaces: 10,
+            min_extra_spaces: 0,
+            fallback_mode: FallbackMode::default(),
+            retry_warn_threshold: 5,
+            slow_call_ms: 1000,
+            max_runtime_secs: 30,
+            labels_only: LabelsOnly::default(),
+            spacing: Spacing::default(),
+            spacing_by_displays: BTreeMap::new(),
+            pinned_display: None,
+            primary_display: None,
+            primary_side: None,
+            reorganize_passes: 2,
+            reserved_display: None,
+            reserved_destination: "s1".to_string(),
+            center_mouse: false,
+            hold_mouse: false,
+            edge_modes: EdgeModes::default(),
+            layout: None,
+            apply_layout: true,
+            missing_space: MissingSpace::default(),
+            socket: None,
+            placements: BTreeMap::new(),
+        }
+    }
+}
+
+fn get_full_path() -> Result<PathBuf> {
+    let home = std::env::var("HOME")?;
+    let path = PathBuf::from(format!("{}/.config/{}", home, CONFIG_FILE));
+    Ok(path)
+}
+
+fn load() -> Result<Config> {
+    let path = get_full_path()?;
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+    let raw = fs::read_to_string(&path)?;
+    let config: Config = toml::from_str(&raw)
+        .with_context(|| format!("Failed to parse config {}", path.display()))?;
+    config
+        .validate()
+        .with_context(|| format!("Invalid config {}", path.display()))?;
+    Ok(config)
+}
+
+// Settings the environment overrides, on top of the config file.
+fn apply_env(mut config: Config) -> Result<Config> {
+    if let Ok(num_spaces) = std::env::var("YABAICTL_NUM_SPACES") {
+        config.num_spaces = num_spaces
+            .parse()
+            .with_context(|| format!("Invalid YABAICTL_NUM_SPACES {:?}", num_spaces))?;
+        config
+            .validate()
+            .with_context(|| format!("Invalid YABAICTL_NUM_SPACES {:?}", num_spaces))?;
+    }
+    Ok(config)
+}
+
+pub fn init() -> Result<()> {
+    let config = apply_env(load()?)?;
+    CONFIG.get_or_init(|| config);
+    Ok(())
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_num_spaces() {
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+        config.num_spaces = 0;
+        assert!(config.validate().is_err());
+        config.num_spaces = 7;
+        assert!(config.validate().is_err());
+    }
+}
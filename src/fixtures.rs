@@ -0,0 +1,12 @@
+use crate::states::YabaiStates;
+
+// The saved states in tests/fixtures, in the format export-state writes.
+pub fn fixture(name: &str) -> YabaiStates {
+    let path = format!(
+        "{}/tests/fixtures/{}.json",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    );
+    let raw = std::fs::read_to_string(&path).unwrap();
+    serde_json::from_str(&raw).unwrap()
+}
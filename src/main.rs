@@ -1,14 +1,30 @@
 #[macro_use]
+extern crate log;
+#[macro_use]
 extern crate serde_derive;
 extern crate serde;
 extern crate serde_json;
 
-use anyhow::{bail, Result};
-use std::convert::TryInto;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::clap::Shell;
 use structopt::StructOpt;
 
-use crate::yabai::{SpaceArg, WindowArg, WindowOp};
+use crate::yabai::{
+    CompositeOp, InsertArg, RestoreOptions, SideArg, SpaceArg, StateArg, WindowArg, WindowOp,
+};
 
+mod config;
+#[cfg(test)]
+mod fixtures;
+mod serve;
 mod states;
 mod yabai;
 
@@ -17,52 +33,588 @@ mod yabai;
     name = "yabaictl",
     about = "A yabai wrapper for better multi-display support ."
 )]
-enum Cli {
-    RestoreSpaces {},
+struct Cli {
+    #[structopt(
+        long,
+        help = "Print the yabai commands that would change anything instead of sending them"
+    )]
+    dry_run: bool,
+    #[structopt(
+        short,
+        long,
+        help = "Also print what yabaictl is doing and every yabai call it makes"
+    )]
+    verbose: bool,
+    #[structopt(
+        short,
+        long,
+        conflicts_with = "verbose",
+        help = "Print nothing but errors"
+    )]
+    quiet: bool,
+    // Debugging aid to see how restore would lay out spaces for a different
+    // number of monitors. Best combined with --dry-run.
+    #[structopt(long, hidden = true)]
+    assume_displays: Option<u32>,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    RestoreSpaces {
+        #[structopt(
+            long,
+            help = "Don't cycle through the spaces, and keep the focused window focused"
+        )]
+        keep_focus: bool,
+        #[structopt(long, help = "Focus the desktop that was focused before once done")]
+        keep_desktop: bool,
+    },
+    EqualizeSpaces {},
+    Relabel {},
+    BenchRestore {
+        #[structopt(
+            long,
+            help = "Print the yabai commands that would change anything instead of sending them"
+        )]
+        dry_run: bool,
+    },
+    Reorganize {
+        #[structopt(long, help = "Only move the windows of this app")]
+        only_app: Option<String>,
+        #[structopt(
+            long,
+            help = "Go by where the windows are now and the placements config, not the saved states"
+        )]
+        live: bool,
+    },
+    RestoreIfDisplaysChanged {
+        #[structopt(
+            long,
+            help = "Don't cycle through the spaces, and keep the focused window focused"
+        )]
+        keep_focus: bool,
+        #[structopt(long, help = "Focus the desktop that was focused before once done")]
+        keep_desktop: bool,
+    },
+    NeedsRestore {
+        #[structopt(long, help = "Print the verdict and the reasons as JSON")]
+        json: bool,
+    },
+    ForceReload {},
+    PrintSocket {},
+    GenerateRules {},
+    CheckCompat {},
+    Pause {},
+    Resume {},
+    ExportState {
+        #[structopt(parse(from_os_str), help = "[the file to write the states to]")]
+        file: PathBuf,
+    },
+    ShowState {
+        #[structopt(long, possible_values = &StateArg::variants(), case_insensitive = true,
+         help = "Only this saved state instead of both")]
+        which: Option<StateArg>,
+        #[structopt(long, help = "Delete the saved state instead of printing it")]
+        clear: bool,
+    },
     FocusSpace {
-        #[structopt(parse(try_from_str = parse_space_arg),
+        #[structopt(parse(try_from_str = parse_space),
          help="[a space number, next, prev, recent]")]
         space: SpaceArg,
+        #[structopt(long, help = "Print the focused and previous spaces as JSON")]
+        json: bool,
+    },
+    ExplainNav {},
+    FocusNextWindow {},
+    FocusPrevWindow {},
+    NextUsed {},
+    PrevUsed {},
+    FocusDesktop {
+        #[structopt(help = "[a composite desktop number]")]
+        desktop: u32,
+        #[structopt(long, possible_values = &SideArg::variants(), case_insensitive = true,
+         default_value = "right")]
+        side: SideArg,
+        #[structopt(long, help = "Leave the other monitor alone")]
+        alone: bool,
     },
     FocusWindow {
         #[structopt(possible_values = &WindowArg::variants(), case_insensitive = true)]
         direction: WindowArg,
+        #[structopt(long, help = "Start from this window instead of the focused one")]
+        window: Option<u32>,
     },
+    FocusRecentWindow {},
+    FocusBack {},
+    MoveWindow {
+        #[structopt(parse(try_from_str = parse_space),
+         help="[a space number, next, prev, recent]")]
+        space: SpaceArg,
+        #[structopt(long, possible_values = &InsertArg::variants(), case_insensitive = true,
+         help = "Warp the window next to this window of the destination space")]
+        insert: Option<InsertArg>,
+    },
+    SendWindow {
+        #[structopt(help = "[a space number]")]
+        space: u32,
+        #[structopt(long, help = "[the id of the window to put it next to]")]
+        after: u32,
+    },
+    SendToRecentDisplay {},
+    ToggleManage {},
+    FocusLargest {},
+    FocusSmallest {},
     SwapWindow {
         #[structopt(possible_values = &WindowArg::variants(), case_insensitive = true)]
         direction: WindowArg,
+        #[structopt(long, help = "Swap this window instead of the focused one")]
+        window: Option<u32>,
     },
     WarpWindow {
         #[structopt(possible_values = &WindowArg::variants(), case_insensitive = true)]
         direction: WindowArg,
+        #[structopt(
+            long,
+            help = "Wrap around within the space instead of crossing to the other monitor"
+        )]
+        target_current_display: bool,
+        #[structopt(long, help = "Warp this window instead of the focused one")]
+        window: Option<u32>,
+    },
+    ListWindows {
+        #[structopt(help = "[a space number, or every space]")]
+        space: Option<u32>,
+        #[structopt(long, help = "Print the windows as JSON")]
+        json: bool,
+        #[structopt(long, help = "Only the windows on this display")]
+        display: Option<u32>,
+    },
+    WindowMap {
+        #[structopt(long, help = "Print the windows as JSON")]
+        json: bool,
+    },
+    FocusedWindow {
+        #[structopt(long, help = "Print the window as JSON")]
+        json: bool,
+    },
+    SelectSpace {
+        #[structopt(long, help = "Print the choices for an external picker instead")]
+        list: bool,
+        #[structopt(long, help = "[a line picked from --list]")]
+        pick: Option<String>,
+    },
+    SelectWindow {
+        #[structopt(long, help = "Print the choices for an external picker instead")]
+        list: bool,
+        #[structopt(long, help = "[a line picked from --list]")]
+        pick: Option<String>,
+    },
+    Diagram {},
+    ListSpaces {
+        #[structopt(long, help = "Print the spaces as JSON")]
+        json: bool,
+        #[structopt(long, help = "Only the spaces on this display")]
+        display: Option<u32>,
+    },
+    WaitVisible {
+        #[structopt(help = "[a space number]")]
+        space: u32,
+        #[structopt(long, default_value = "5", help = "Seconds to wait before giving up")]
+        timeout: f64,
+    },
+    SwapMonitors {},
+    Partner {
+        #[structopt(help = "[a space number]")]
+        space: u32,
+    },
+    RegroupSpace {
+        #[structopt(help = "[a space number]")]
+        space: u32,
+        #[structopt(help = "[the composite desktop to move the space into]")]
+        group: u32,
+    },
+    Batch {
+        #[structopt(
+            parse(from_os_str),
+            help = "[a file of yabaictl commands, one per line, or stdin when left out]"
+        )]
+        file: Option<PathBuf>,
+    },
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+    Serve {
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "The unix socket to answer JSON requests on"
+        )]
+        socket: PathBuf,
+    },
+    ApplyToComposite {
+        #[structopt(possible_values = &CompositeOp::variants(), case_insensitive = true)]
+        op: CompositeOp,
+        #[structopt(help = "[the opacity to apply, 0.0 to 1.0]")]
+        value: Option<f32>,
     },
 }
 
 fn main() -> Result<()> {
-    match Cli::from_args() {
-        Cli::FocusWindow { direction } => yabai::operate_window(WindowOp::Focus, direction)?,
-        Cli::SwapWindow { direction } => yabai::operate_window(WindowOp::Swap, direction)?,
-        Cli::WarpWindow { direction } => yabai::operate_window(WindowOp::Warp, direction)?,
-        Cli::FocusSpace { space } => yabai::focus_space(space)?,
-        Cli::RestoreSpaces {} => yabai::restore_spaces()?,
+    // Parsing the arguments needs the config, for the range of spaces, but a
+    // broken config shouldn't get in the way of --help, --version or
+    // completions. Those go with the defaults.
+    let loaded = config::init();
+
+    let cli = Cli::from_args();
+    init_logging(cli.verbose, cli.quiet);
+    if let Err(e) = loaded {
+        if cli.command.needs_config() {
+            return Err(e);
+        }
+        warn!("{:#}, using the default config", e);
+    }
+    if !cli.command.is_long_running() {
+        start_watchdog(config::get().max_runtime_secs);
+    }
+    yabai::set_dry_run(cli.dry_run);
+    if let Some(num_displays) = cli.assume_displays {
+        states::assume_displays(num_displays);
     }
 
+    if !run(cli.command)? {
+        process::exit(1);
+    }
     Ok(())
 }
 
-fn parse_space_arg(src: &str) -> Result<SpaceArg> {
+// Returns false for a check that came out negative, which exits 1 on its
+// own but isn't an error, so batch and serve carry on.
+fn run(command: Command) -> Result<bool> {
+    match command {
+        Command::FocusWindow { direction, window } => {
+            yabai::operate_window(WindowOp::Focus, direction, false, window)?
+        }
+        Command::SwapWindow { direction, window } => {
+            yabai::operate_window(WindowOp::Swap, direction, false, window)?
+        }
+        Command::WarpWindow {
+            direction,
+            target_current_display,
+            window,
+        } => yabai::operate_window(WindowOp::Warp, direction, target_current_display, window)?,
+        Command::FocusRecentWindow {} => yabai::focus_recent_window()?,
+        Command::FocusBack {} => yabai::focus_back()?,
+        Command::MoveWindow { space, insert } => yabai::move_window(space, insert)?,
+        Command::SendWindow { space, after } => yabai::send_window(space, after)?,
+        Command::SendToRecentDisplay {} => yabai::send_to_recent_display()?,
+        Command::ToggleManage {} => yabai::toggle_manage()?,
+        Command::FocusLargest {} => yabai::focus_by_area(true)?,
+        Command::FocusSmallest {} => yabai::focus_by_area(false)?,
+        Command::FocusDesktop {
+            desktop,
+            side,
+            alone,
+        } => yabai::focus_desktop(desktop, side, alone)?,
+        Command::ExplainNav {} => yabai::explain_nav()?,
+        Command::FocusNextWindow {} => yabai::focus_next_window(true)?,
+        Command::FocusPrevWindow {} => yabai::focus_next_window(false)?,
+        Command::NextUsed {} => yabai::focus_used(true)?,
+        Command::PrevUsed {} => yabai::focus_used(false)?,
+        Command::FocusSpace { space, json } => yabai::focus_space(space, json)?,
+        Command::RestoreSpaces {
+            keep_focus,
+            keep_desktop,
+        } => yabai::restore_spaces(&RestoreOptions {
+            keep_focus,
+            keep_desktop,
+        })?,
+        Command::EqualizeSpaces {} => yabai::equalize_spaces()?,
+        Command::Relabel {} => yabai::relabel()?,
+        Command::BenchRestore { dry_run } => {
+            if dry_run {
+                yabai::set_dry_run(true);
+            }
+            yabai::bench_restore()?
+        }
+        Command::Reorganize { only_app, live } => yabai::reorganize(only_app.as_deref(), live)?,
+        Command::RestoreIfDisplaysChanged {
+            keep_focus,
+            keep_desktop,
+        } => yabai::restore_if_displays_changed(&RestoreOptions {
+            keep_focus,
+            keep_desktop,
+        })?,
+        // Exits 0 when a restore is needed, so it reads naturally in an `if`.
+        Command::NeedsRestore { json } => return yabai::needs_restore(json),
+        Command::ForceReload {} => yabai::force_reload()?,
+        Command::PrintSocket {} => yabai::print_socket()?,
+        Command::GenerateRules {} => yabai::generate_rules()?,
+        Command::CheckCompat {} => yabai::check_compat()?,
+        Command::Pause {} => yabai::pause(true)?,
+        Command::Resume {} => yabai::pause(false)?,
+        Command::ExportState { file } => yabai::export_state(&file)?,
+        Command::ShowState { which, clear } => yabai::show_state(which, clear)?,
+        Command::ListWindows {
+            space,
+            json,
+            display,
+        } => yabai::list_windows(space, json, display)?,
+        Command::WindowMap { json } => yabai::window_map(json)?,
+        Command::FocusedWindow { json } => yabai::focused_window(json)?,
+        Command::SelectSpace { list, pick } => yabai::select_space(list, pick.as_deref())?,
+        Command::SelectWindow { list, pick } => yabai::select_window(list, pick.as_deref())?,
+        Command::Diagram {} => yabai::diagram()?,
+        Command::ListSpaces { json, display } => yabai::list_spaces(json, display)?,
+        Command::WaitVisible { space, timeout } => {
+            yabai::wait_visible(space, Duration::from_secs_f64(timeout))?
+        }
+        Command::SwapMonitors {} => yabai::swap_monitors()?,
+        Command::Partner { space } => yabai::partner(space)?,
+        Command::RegroupSpace { space, group } => yabai::regroup_space(space, group)?,
+        Command::ApplyToComposite { op, value } => yabai::apply_to_composite(op, value)?,
+        Command::Batch { file } => batch(file)?,
+        Command::Serve { socket } => serve::serve(&socket)?,
+        Command::Completions { shell } => completions(shell)?,
+    }
+
+    Ok(true)
+}
+
+// Run the commands of a file, one per line, in this one process. Stops at
+// the first one that fails, not at a check that comes out negative.
+fn batch(file: Option<PathBuf>) -> Result<()> {
+    let script = match &file {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?,
+        None => {
+            let mut script = String::new();
+            io::stdin().read_to_string(&mut script)?;
+            script
+        }
+    };
+    yabai::enable_query_cache();
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let args = split_line(line).with_context(|| format!("line {}", number + 1))?;
+        let command = Command::from_iter_safe(iter::once("yabaictl".to_string()).chain(args))
+            .with_context(|| format!("line {}: {}", number + 1, line))?;
+        if let Command::Batch { .. } = command {
+            bail!("line {}: batch can't run another batch", number + 1);
+        }
+        rearm_watchdog();
+        run(command).with_context(|| format!("line {}: {}", number + 1, line))?;
+    }
+    Ok(())
+}
+
+// Print the completion script for the shell. The space arguments take any
+// number as well, so clap can't offer their keywords on its own. For zsh,
+// put them in instead of clap's fallback to file names.
+fn completions(shell: Shell) -> Result<()> {
+    print!("{}", completion_script(shell)?);
+    Ok(())
+}
+
+fn completion_script(shell: Shell) -> Result<String> {
+    let mut script = Vec::new();
+    Cli::clap().gen_completions_to("yabaictl", shell, &mut script);
+    let mut script = String::from_utf8(script)?;
+    if let Shell::Zsh = shell {
+        script = script.replace(
+            "next, prev, recent\\]:_files'",
+            "next, prev, recent\\]:(next prev recent)'",
+        );
+    }
+    Ok(script)
+}
+
+// Split a batch line into arguments like a shell would, minus everything
+// but whitespace and quotes.
+fn split_line(line: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg: Option<String> = None;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => arg.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (None, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        bail!("Unterminated quote in {:?}", line);
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+impl Command {
+    // Commands that are expected to outlive the watchdog, have a deadline of
+    // their own or wait for the user.
+    fn is_long_running(&self) -> bool {
+        matches!(
+            self,
+            Command::ForceReload {}
+                | Command::Serve { .. }
+                | Command::WaitVisible { .. }
+                | Command::SelectSpace {
+                    list: false,
+                    pick: None
+                }
+                | Command::SelectWindow {
+                    list: false,
+                    pick: None
+                }
+        )
+    }
+
+    // Everything but completions acts on the spaces the config describes.
+    fn needs_config(&self) -> bool {
+        !matches!(self, Command::Completions { .. })
+    }
+}
+
+// Warnings only by default, so that scripts and status bars get nothing
+// on stderr unless something is off.
+fn init_logging(verbose: bool, quiet: bool) {
+    let level = if verbose {
+        log::LevelFilter::Debug
+    } else if quiet {
+        log::LevelFilter::Error
+    } else {
+        log::LevelFilter::Warn
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format(|buf, record| match record.level() {
+            log::Level::Error => writeln!(buf, "Error: {}", record.args()),
+            log::Level::Warn => writeln!(buf, "Warning: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
+}
+
+// When the watchdog aborts, unless rearm_watchdog() pushes it back.
+static WATCHDOG_DEADLINE: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn start_watchdog(max_runtime_secs: u64) {
+    if max_runtime_secs == 0 {
+        return;
+    }
+    rearm_watchdog();
+    thread::spawn(move || loop {
+        let deadline = WATCHDOG_DEADLINE.lock().unwrap().unwrap();
+        let now = Instant::now();
+        if now >= deadline {
+            error!(
+                "Aborting after {}s, yabai is not responding",
+                max_runtime_secs
+            );
+            process::exit(1);
+        }
+        thread::sleep(deadline - now);
+    });
+}
+
+// Give the watchdog, if there is one, its full time again. batch does this
+// for every line, so a long file of quick commands isn't cut off halfway.
+fn rearm_watchdog() {
+    let max_runtime_secs = config::get().max_runtime_secs;
+    if max_runtime_secs == 0 {
+        return;
+    }
+    *WATCHDOG_DEADLINE.lock().unwrap() =
+        Some(Instant::now() + Duration::from_secs(max_runtime_secs));
+}
+
+fn parse_space(src: &str) -> Result<SpaceArg> {
+    parse_space_arg(src, yabai::num_spaces())
+}
+
+fn parse_space_arg(src: &str, num_spaces: u32) -> Result<SpaceArg> {
     match src {
-        "next" => return Ok(SpaceArg::Next),
-        "prev" => return Ok(SpaceArg::Prev),
-        "recent" => return Ok(SpaceArg::Recent),
-        "third" => return Ok(SpaceArg::Third),
-        "fourth" => return Ok(SpaceArg::Fourth),
+        "next" => Ok(SpaceArg::Next),
+        "prev" => Ok(SpaceArg::Prev),
+        "recent" => Ok(SpaceArg::Recent),
+        "third" => Ok(SpaceArg::Third),
+        "fourth" => Ok(SpaceArg::Fourth),
         _ => {
-            let space = u32::from_str_radix(src, 10)?;
-            if space == 0 || space > yabai::NUM_SPACES {
+            let space = src.parse::<u32>()?;
+            if space == 0 || space > num_spaces {
                 bail!("Space {} out of range", space);
             }
-            return Ok(SpaceArg::Space(space.try_into()?));
+            Ok(SpaceArg::Space(space))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_space_arg_keywords() {
+        assert_eq!(parse_space_arg("next", 10).unwrap(), SpaceArg::Next);
+        assert_eq!(parse_space_arg("prev", 10).unwrap(), SpaceArg::Prev);
+        assert_eq!(parse_space_arg("recent", 10).unwrap(), SpaceArg::Recent);
+        assert_eq!(parse_space_arg("third", 10).unwrap(), SpaceArg::Third);
+        assert_eq!(parse_space_arg("fourth", 10).unwrap(), SpaceArg::Fourth);
+    }
+
+    #[test]
+    fn parse_space_arg_numbers() {
+        assert_eq!(parse_space_arg("1", 10).unwrap(), SpaceArg::Space(1));
+        assert_eq!(parse_space_arg("10", 10).unwrap(), SpaceArg::Space(10));
+        assert_eq!(parse_space_arg("16", 16).unwrap(), SpaceArg::Space(16));
+    }
+
+    #[test]
+    fn parse_space_arg_out_of_range() {
+        assert!(parse_space_arg("0", 10).is_err());
+        assert!(parse_space_arg("11", 10).is_err());
+        assert!(parse_space_arg("7", 6).is_err());
+    }
+
+    #[test]
+    fn zsh_completion_values() {
+        let script = completion_script(Shell::Zsh).unwrap();
+        assert!(script.contains(":direction:(North East South West)"));
+        assert!(script.contains(":(next prev recent)'"));
+    }
+
+    #[test]
+    fn split_line_quotes() {
+        assert_eq!(
+            split_line("  focus-space  next ").unwrap(),
+            vec!["focus-space", "next"]
+        );
+        assert_eq!(
+            split_line("select-window --pick '12\tSlack \"general\"'").unwrap(),
+            vec!["select-window", "--pick", "12\tSlack \"general\""]
+        );
+        assert_eq!(
+            split_line("reorganize --only-app \"\"").unwrap(),
+            vec!["reorganize", "--only-app", ""]
+        );
+        assert!(split_line("reorganize --only-app 'Slack").is_err());
+    }
+
+    #[test]
+    fn parse_space_arg_invalid() {
+        assert!(parse_space_arg("", 10).is_err());
+        assert!(parse_space_arg("-1", 10).is_err());
+        assert!(parse_space_arg("s1", 10).is_err());
+        assert!(parse_space_arg("Next", 10).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::thread;
+use structopt::StructOpt;
+
+use crate::{run, split_line, Command};
+
+// One line of the protocol, e.g. {"cmd":"focus-space","arg":"next"}. arg
+// holds the rest of the command line, split the way batch splits it.
+#[derive(Deserialize, Debug)]
+struct Request {
+    cmd: String,
+    #[serde(default)]
+    arg: Option<String>,
+}
+
+// The answer to a request. output is what the command printed, as JSON
+// when it printed JSON, e.g. with --json, or else as a string. exit is the
+// code the command would have exited with on its own, when not 0, e.g. 1
+// from needs-restore when nothing needs restoring.
+#[derive(Serialize, Debug)]
+struct Response {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+extern "C" {
+    fn dup(fd: i32) -> i32;
+    fn dup2(src: i32, dst: i32) -> i32;
+}
+
+// Answer requests on a unix socket, one JSON object per line each way, in
+// this one process. Connections are served one at a time, so commands never
+// run concurrently.
+pub fn serve(path: &Path) -> Result<()> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        // Left behind by a previous serve.
+        fs::remove_file(path)?;
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("Failed to bind {}", path.display()))?;
+    info!("Listening on {}", path.display());
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = serve_connection(stream) {
+                    warn!("connection failed: {:#}", e);
+                }
+            }
+            Err(e) => warn!("failed to accept a connection: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn serve_connection(stream: UnixStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match handle(&line) {
+            Ok((success, output)) => Response {
+                ok: true,
+                exit: if success { None } else { Some(1) },
+                output: Some(output_value(output)),
+                error: None,
+            },
+            Err(e) => Response {
+                ok: false,
+                exit: None,
+                output: None,
+                error: Some(format!("{:#}", e)),
+            },
+        };
+        writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+    }
+    Ok(())
+}
+
+fn handle(line: &str) -> Result<(bool, String)> {
+    let request: Request = serde_json::from_str(line).context("Failed to parse request")?;
+    let args = match &request.arg {
+        Some(arg) => split_line(arg)?,
+        None => Vec::new(),
+    };
+    let command = Command::from_iter_safe(
+        iter::once("yabaictl".to_string())
+            .chain(iter::once(request.cmd))
+            .chain(args),
+    )?;
+    if let Command::Batch { .. } | Command::Serve { .. } = command {
+        bail!("serve can't run batch or serve");
+    }
+    let (result, output) = capture_stdout(|| {
+        // A panicking command shouldn't take the server down with it.
+        panic::catch_unwind(AssertUnwindSafe(|| run(command)))
+    })?;
+    match result {
+        Ok(result) => result.map(|success| (success, output)),
+        Err(_) => bail!("the command panicked"),
+    }
+}
+
+fn output_value(output: String) -> Value {
+    serde_json::from_str(&output).unwrap_or(Value::String(output))
+}
+
+// Run f with the process' stdout going into a pipe, and return what it
+// printed. The commands print their results instead of returning them.
+fn capture_stdout<T>(f: impl FnOnce() -> T) -> Result<(T, String)> {
+    let (mut reader, writer) = io::pipe()?;
+    // Drain the pipe as it fills, so a long listing can't block the command.
+    let reading = thread::spawn(move || {
+        let mut output = String::new();
+        reader.read_to_string(&mut output).map(|_| output)
+    });
+
+    io::stdout().flush()?;
+    let saved = unsafe { dup(1) };
+    if saved < 0 {
+        bail!("Failed to duplicate stdout: {}", io::Error::last_os_error());
+    }
+    let saved = unsafe { OwnedFd::from_raw_fd(saved) };
+    if unsafe { dup2(writer.as_raw_fd(), 1) } < 0 {
+        bail!("Failed to redirect stdout: {}", io::Error::last_os_error());
+    }
+    drop(writer);
+
+    let result = f();
+
+    io::stdout().flush()?;
+    if unsafe { dup2(saved.as_raw_fd(), 1) } < 0 {
+        bail!("Failed to restore stdout: {}", io::Error::last_os_error());
+    }
+    let output = reading.join().expect("stdout reader panicked")?;
+    Ok((result, output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_as_json_or_string() {
+        assert_eq!(
+            output_value("{\"label\":\"s2\"}\n".to_string()),
+            serde_json::json!({"label": "s2"})
+        );
+        assert_eq!(
+            output_value("s2\ts4\n".to_string()),
+            Value::String("s2\ts4\n".to_string())
+        );
+    }
+
+    #[test]
+    fn request_without_arg() {
+        let request: Request = serde_json::from_str("{\"cmd\":\"pause\"}").unwrap();
+        assert_eq!(request.cmd, "pause");
+        assert!(request.arg.is_none());
+    }
+}
@@ -1,39 +1,365 @@
 use anyhow::{Context, Result};
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::convert::TryInto;
+use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::yabai;
 
 static YABAICTL_STATE: &str = "yabaictl";
 static YABAI_STATE: &str = "yabai";
 
-#[derive(Serialize, Deserialize, Debug)]
+// Overrides num_displays() when set. See --assume-displays.
+static ASSUMED_DISPLAYS: AtomicU32 = AtomicU32::new(0);
+
+// How many focused windows to remember for focus-recent-window.
+const RECENT_WINDOWS_CAP: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct YabaictlStates {
-    pub recent: u32,
+    // The space each display was on before the last focus-space, keyed by
+    // display uuid.
+    #[serde(default)]
+    pub recent_by_display: BTreeMap<String, u32>,
+    // Most recently focused window ids, most recent first.
+    #[serde(default)]
+    pub recent_windows: Vec<u32>,
+    // Position in recent_windows while cycling with focus-recent-window. 0
+    // when not cycling.
+    #[serde(default)]
+    pub recent_window_cursor: usize,
+    // Windows the user took out of yabai's management with toggle-manage.
+    // Window ids don't survive app restarts, so they are kept by app and
+    // title.
+    #[serde(default)]
+    pub keep_floating: BTreeSet<WindowKey>,
+    // The labeled space each display was last seen on, keyed by display
+    // uuid, for when it is showing a fullscreen app's space.
+    #[serde(default)]
+    pub last_labeled_by_display: BTreeMap<String, u32>,
+    // YabaiStates::display_fingerprint() as of the last restore.
+    #[serde(default)]
+    pub display_fingerprint: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WindowKey {
+    pub app: String,
+    pub title: String,
+}
+
+impl YabaictlStates {
+    // Update recent_windows with the currently focused window. Returns true
+    // if anything changed.
+    pub fn observe_focused_window(&mut self, states: &YabaiStates) -> bool {
+        let before = (self.recent_windows.clone(), self.recent_window_cursor);
+        self.recent_windows
+            .retain(|id| states.find_window(id).is_some());
+        if let Some(window) = states.focused_window() {
+            let cycling = self.recent_window_cursor > 0
+                && self.recent_windows.get(self.recent_window_cursor) == Some(&window.id);
+            if !cycling {
+                self.recent_window_cursor = 0;
+                self.recent_windows.retain(|&id| id != window.id);
+                self.recent_windows.insert(0, window.id);
+                self.recent_windows.truncate(RECENT_WINDOWS_CAP);
+            }
+        }
+        if self.recent_window_cursor >= self.recent_windows.len() {
+            self.recent_window_cursor = 0;
+        }
+        before != (self.recent_windows.clone(), self.recent_window_cursor)
+    }
+
+    // Update last_labeled_by_display with the focused space. Returns true if
+    // anything changed.
+    pub fn observe_focused_space(&mut self, states: &YabaiStates) -> bool {
+        let label_index = match states.focused_space().and_then(Space::label_index) {
+            None => return false,
+            Some(label_index) => label_index,
+        };
+        let display = match states.focused_display_index() {
+            None => return false,
+            Some(display) => states.display_uuid(display),
+        };
+        self.last_labeled_by_display.insert(display, label_index) != Some(label_index)
+    }
+
+    // Fix up the recent spaces after spaces went away, e.g. destroyed by a
+    // restore. Returns true if anything changed.
+    pub fn prune_recent(&mut self, states: &YabaiStates) -> bool {
+        let before = self.recent_by_display.clone();
+        let mut pruned = BTreeMap::new();
+        for (display, &recent) in self.recent_by_display.iter() {
+            if let Some(recent) = states.valid_recent(recent) {
+                pruned.insert(display.clone(), recent);
+            }
+        }
+        self.recent_by_display = pruned;
+        before != self.recent_by_display
+    }
+
+    // What doesn't add up against the given states, for show-state.
+    pub fn problems(&self, states: &YabaiStates) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (display, &recent) in self.recent_by_display.iter() {
+            if !states.displays.is_empty()
+                && !states.displays.iter().any(|d| d.uuid == *display)
+                && display.parse::<u32>().is_err()
+            {
+                problems.push(format!(
+                    "recent for display {} which is not connected",
+                    display
+                ));
+            }
+            match states.valid_recent(recent) {
+                Some(valid) if valid == recent => {}
+                Some(valid) => problems.push(format!(
+                    "recent s{} for display {} is out of range, it would become s{}",
+                    recent, display, valid
+                )),
+                None => problems.push(format!(
+                    "recent s{} for display {} is out of range",
+                    recent, display
+                )),
+            }
+        }
+        for window_id in self.recent_windows.iter() {
+            if states.find_window(window_id).is_none() {
+                problems.push(format!("recent window {} no longer exists", window_id));
+            }
+        }
+        if self.recent_window_cursor > 0 && self.recent_window_cursor >= self.recent_windows.len() {
+            problems.push(format!(
+                "recent window cursor {} is past the {} recent windows",
+                self.recent_window_cursor,
+                self.recent_windows.len()
+            ));
+        }
+        if self.display_fingerprint != states.display_fingerprint() {
+            problems.push("the displays changed since the last restore".to_string());
+        }
+        problems
+    }
+
+    // Stop cycling with focus-recent-window and make the window it landed
+    // on the most recent one.
+    pub fn end_window_cycle(&mut self) {
+        if self.recent_window_cursor == 0 {
+            return;
+        }
+        let window_id = self.recent_windows.remove(self.recent_window_cursor);
+        self.recent_windows.insert(0, window_id);
+        self.recent_window_cursor = 0;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct YabaiStates {
     pub spaces: Vec<Space>,
     pub displays: Vec<Display>,
     pub windows: Vec<Window>,
+    // Set when some of the states could not be queried. A partial snapshot
+    // is never saved, so it cannot clobber the good one on disk.
+    #[serde(skip)]
+    pub partial: bool,
+    // The index of the display configured as pinned_display, when it is
+    // connected along with others.
+    #[serde(skip)]
+    pub pinned_display: Option<u32>,
+    // Set when primary_display puts the even labels on display 2.
+    #[serde(skip)]
+    pub sides_swapped: bool,
+    // The index of the display configured as reserved_display, when it is
+    // connected along with others.
+    #[serde(skip)]
+    pub reserved_display: Option<u32>,
 }
 
 impl YabaiStates {
     pub fn num_spaces(&self) -> u32 {
-        return self.spaces.len().try_into().unwrap();
+        self.spaces.len().try_into().unwrap()
+    }
+
+    // The display indexes in the order the displays physically are, left
+    // to right. yabai numbers them its own way.
+    pub fn displays_left_to_right(&self) -> Vec<u32> {
+        displays_left_to_right(&self.displays)
     }
 
     pub fn num_displays(&self) -> u32 {
-        return self.displays.len().try_into().unwrap();
+        let assumed = ASSUMED_DISPLAYS.load(Ordering::Relaxed);
+        if assumed > 0 {
+            return assumed;
+        }
+        if self.displays.is_empty() {
+            // The display states are missing. Count the displays the spaces
+            // are on instead.
+            let displays: HashSet<u32> = self.spaces.iter().map(|space| space.display).collect();
+            return displays.len().try_into().unwrap();
+        }
+        self.displays.len().try_into().unwrap()
+    }
+
+    // Identifies the monitor arrangement: which displays are connected and
+    // where they sit. Changes to spaces don't affect it.
+    pub fn display_fingerprint(&self) -> String {
+        let mut displays: Vec<String> = self
+            .displays
+            .iter()
+            .map(|display| {
+                format!(
+                    "{}@{},{},{}x{}",
+                    display.uuid,
+                    display.frame.x,
+                    display.frame.y,
+                    display.frame.w,
+                    display.frame.h
+                )
+            })
+            .collect();
+        displays.sort();
+        displays.join(";")
+    }
+
+    // The displays that take part in the composite desktops.
+    pub fn composite_displays(&self) -> u32 {
+        match self.pinned_display {
+            Some(_) => self.num_displays() - 1,
+            None => self.num_displays(),
+        }
+    }
+
+    // True for the snapshots yabai gives out while it is still starting up:
+    // no spaces, no displays or nothing focused. Nothing sensible can be
+    // computed from those.
+    pub fn is_degenerate(&self) -> bool {
+        self.spaces.is_empty() || self.num_displays() == 0 || self.focused_space().is_none()
+    }
+
+    // What doesn't add up within the states, for show-state.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut labels = HashSet::new();
+        for space in self.spaces.iter() {
+            if !space.label.is_empty() && !labels.insert(&space.label) {
+                problems.push(format!("label {} is on more than one space", space.label));
+            }
+            if !self.displays.is_empty()
+                && !self
+                    .displays
+                    .iter()
+                    .any(|display| display.index == space.display)
+            {
+                problems.push(format!(
+                    "space {} is on display {}, which is missing",
+                    space.index, space.display
+                ));
+            }
+        }
+        let focused = self.spaces.iter().filter(|space| space.has_focus).count();
+        if focused != 1 {
+            problems.push(format!("{} spaces have focus", focused));
+        }
+        for window in self.windows.iter() {
+            if !self.spaces.iter().any(|space| space.index == window.space) {
+                problems.push(format!(
+                    "window {} is on space {}, which is missing",
+                    window.id, window.space
+                ));
+            }
+        }
+        problems
+    }
+
+    // Right after a reload, yabai lists no windows in any space but the
+    // focused one until each has been visited, even though Window.space
+    // says otherwise. Cold when that is the case for most of the other
+    // spaces with windows.
+    pub fn is_cold(&self) -> bool {
+        let mut with_windows = 0;
+        let mut stale = 0;
+        for space in self.spaces.iter().filter(|space| !space.has_focus) {
+            let has_windows = self.windows.iter().any(|window| {
+                window.space == space.index && !window.is_minimized && !window.is_hidden
+            });
+            if has_windows {
+                with_windows += 1;
+                if space.windows.is_empty() {
+                    stale += 1;
+                }
+            }
+        }
+        stale * 2 > with_windows
     }
 
     pub fn focused_space(&self) -> Option<&Space> {
         self.spaces.iter().find(|space| space.has_focus)
     }
 
+    // Older yabai versions don't report has-focus for displays. Go by the
+    // focused space then.
+    pub fn focused_display(&self) -> Option<&Display> {
+        if let Some(display) = self.displays.iter().find(|display| display.has_focus) {
+            return Some(display);
+        }
+        let focused_space = self.focused_space()?;
+        self.displays
+            .iter()
+            .find(|display| display.index == focused_space.display)
+    }
+
+    // Like focused_display(), but still works when the display states are
+    // missing.
+    pub fn focused_display_index(&self) -> Option<u32> {
+        match self.focused_display() {
+            Some(display) => Some(display.index),
+            None => self.focused_space().map(|space| space.display),
+        }
+    }
+
+    // True when the focused display has no s-labeled spaces at all.
+    pub fn focused_display_unlabeled(&self) -> bool {
+        match self.focused_display_index() {
+            None => false,
+            Some(display) => !self
+                .spaces
+                .iter()
+                .any(|space| space.display == display && space.label_index().is_some()),
+        }
+    }
+
+    // Checks Window.space as well, since the windows arrays can be stale.
+    pub fn space_has_windows(&self, space_index: u32) -> bool {
+        self.windows
+            .iter()
+            .any(|window| window.space == space_index)
+            || self
+                .spaces
+                .iter()
+                .any(|space| space.index == space_index && !space.windows.is_empty())
+    }
+
+    // The given recent space if it still exists, or else the closest one
+    // below it. 0 stands for the reserved space and is left alone.
+    pub fn valid_recent(&self, recent: u32) -> Option<u32> {
+        if recent == 0
+            || (recent <= self.num_spaces() && self.find_space_by_label_index(recent).is_some())
+        {
+            return Some(recent);
+        }
+        (1..recent.min(self.num_spaces() + 1))
+            .rev()
+            .find(|&index| self.find_space_by_label_index(index).is_some())
+    }
+
     pub fn find_space_by_label(&self, label: &str) -> Option<&Space> {
         self.spaces.iter().find(|&space| space.label == label)
     }
@@ -42,7 +368,7 @@ impl YabaiStates {
         self.spaces
             .iter()
             // An app going fullscreen gets its own space. MacOS is... weird.
-            .find(|&space| space.label == "" && space.is_native_fullscreen)
+            .find(|&space| space.label.is_empty() && space.is_native_fullscreen)
     }
 
     pub fn find_space_by_label_index(&self, label_index: u32) -> Option<&Space> {
@@ -50,33 +376,69 @@ impl YabaiStates {
         self.spaces.iter().find(|&space| space.label == label)
     }
 
+    // A stable key for the display at the given index. Falls back to the
+    // index itself when the display states are unavailable.
+    pub fn display_uuid(&self, display_index: u32) -> String {
+        match self
+            .displays
+            .iter()
+            .find(|display| display.index == display_index)
+        {
+            Some(display) if !display.uuid.is_empty() => display.uuid.clone(),
+            _ => display_index.to_string(),
+        }
+    }
+
+    pub fn focused_window(&self) -> Option<&Window> {
+        self.windows.iter().find(|window| window.has_focus)
+    }
+
+    pub fn find_window(&self, window_id: &u32) -> Option<&Window> {
+        self.windows.iter().find(|&window| window.id == *window_id)
+    }
+
+    // Windows missing from the states are assumed to be movable.
+    pub fn can_move(&self, window_id: &u32) -> bool {
+        self.find_window(window_id)
+            .is_none_or(|window| window.can_move)
+    }
+
     pub fn find_window_id_in_space(&self, space_label: &str, window_id: &u32) -> Option<&u32> {
-        let space = self.find_space_by_label(space_label);
-        match space {
-            None => return None,
-            Some(space) => return space.find_window_id(window_id),
-        };
+        let space = self.find_space_by_label(space_label)?;
+        match self.find_window(window_id) {
+            // Window.space is authoritative. While yabai is in transition, a
+            // window can briefly be listed in more than one space, and
+            // after a reload the windows arrays can be empty.
+            Some(window) if window.space != space.index => None,
+            Some(window) => Some(&window.id),
+            None => space.find_window_id(window_id),
+        }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Space {
+    #[serde(default)]
     id: u32,
+    #[serde(default)]
     uuid: String,
     pub index: u32,
     pub label: String,
+    #[serde(default)]
     r#type: String,
-    display: u32,
+    #[serde(default)]
+    pub display: u32,
+    #[serde(default)]
     pub windows: Vec<u32>,
-    #[serde(rename = "first-window")]
+    #[serde(rename = "first-window", default)]
     pub first_window: u32,
-    #[serde(rename = "last-window")]
+    #[serde(rename = "last-window", default)]
     pub last_window: u32,
-    #[serde(rename = "has-focus")]
+    #[serde(rename = "has-focus", default)]
     pub has_focus: bool,
-    #[serde(rename = "is-visible")]
+    #[serde(rename = "is-visible", default)]
     pub is_visible: bool,
-    #[serde(rename = "is-native-fullscreen")]
+    #[serde(rename = "is-native-fullscreen", default)]
     is_native_fullscreen: bool,
 }
 
@@ -89,88 +451,151 @@ impl Space {
         if !self.label.starts_with("s") {
             return None;
         }
-        let index = u32::from_str_radix(&self.label[1..], 10);
-        match index {
-            Ok(index) => Some(index),
-            Err(_) => None,
-        }
+        self.label[1..].parse::<u32>().ok()
     }
 }
 
+// yabai 4 only reports id, uuid, index, frame and spaces. label and
+// has-focus came later, so everything but the index is optional.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Display {
-    id: u32,
-    uuid: String,
-    index: u32,
-    frame: Frame,
-    spaces: Vec<u32>,
+    #[serde(default)]
+    pub id: u32,
+    #[serde(default)]
+    pub uuid: String,
+    pub index: u32,
+    #[serde(default)]
+    pub label: String,
+    #[serde(default)]
+    pub frame: Frame,
+    #[serde(default)]
+    pub spaces: Vec<u32>,
+    #[serde(rename = "has-focus", default)]
+    pub has_focus: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Frame {
-    x: f32,
-    y: f32,
-    w: f32,
-    h: f32,
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Frame {
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
+impl Frame {
+    pub fn area(&self) -> f32 {
+        self.w * self.h
+    }
+
+    // In global coordinates, which go negative left of and above the
+    // display with the menu bar.
+    pub fn center(&self) -> (f32, f32) {
+        (self.x + self.w / 2.0, self.y + self.h / 2.0)
+    }
+
+    // Goes by the centers, so overlapping or differently sized displays
+    // still come out in a sensible order.
+    pub fn is_left_of(&self, other: &Frame) -> bool {
+        self.center().0.total_cmp(&other.center().0).is_lt()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Window {
-    id: u32,
+    pub id: u32,
+    #[serde(default)]
     pid: u32,
-    app: String,
-    title: String,
-    frame: Frame,
+    #[serde(default)]
+    pub app: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub frame: Frame,
+    #[serde(default)]
     role: String,
+    #[serde(default)]
     subrole: String,
-    display: u32,
-    space: u32,
+    #[serde(default)]
+    pub display: u32,
+    pub space: u32,
+    #[serde(default)]
     level: i32,
+    #[serde(default)]
     opacity: f32,
-    #[serde(rename = "split-type")]
-    split_type: String,
-    #[serde(rename = "stack-index")]
+    #[serde(rename = "split-type", default)]
+    pub split_type: String,
+    #[serde(rename = "stack-index", default)]
     stack_index: u32,
 
-    #[serde(rename = "can-move")]
-    can_move: bool,
-    #[serde(rename = "can-resize")]
+    // Assume a window can move when yabai doesn't say. Skipping it would
+    // be worse than a rejected move.
+    #[serde(rename = "can-move", default = "can_move_default")]
+    pub can_move: bool,
+    #[serde(rename = "can-resize", default)]
     can_resize: bool,
-    #[serde(rename = "has-focus")]
+    #[serde(rename = "has-focus", default)]
     has_focus: bool,
-    #[serde(rename = "has-shadow")]
+    #[serde(rename = "has-shadow", default)]
     has_shadow: bool,
-    #[serde(rename = "has-border")]
+    #[serde(rename = "has-border", default)]
     has_border: bool,
-    #[serde(rename = "has-parent-zoom")]
+    #[serde(rename = "has-parent-zoom", default)]
     has_parent_zoom: bool,
-    #[serde(rename = "has-fullscreen-zoom")]
+    #[serde(rename = "has-fullscreen-zoom", default)]
     has_fullscreen_zoom: bool,
-    #[serde(rename = "is-native-fullscreen")]
+    #[serde(rename = "is-native-fullscreen", default)]
     is_native_fullscreen: bool,
-    #[serde(rename = "is-visible")]
+    #[serde(rename = "is-visible", default)]
     is_visible: bool,
-    #[serde(rename = "is-minimized")]
-    is_minimized: bool,
-    #[serde(rename = "is-hidden")]
-    is_hidden: bool,
-    #[serde(rename = "is-floating")]
-    is_floating: bool,
-    #[serde(rename = "is-sticky")]
+    #[serde(rename = "is-minimized", default)]
+    pub is_minimized: bool,
+    #[serde(rename = "is-hidden", default)]
+    pub is_hidden: bool,
+    #[serde(rename = "is-floating", default)]
+    pub is_floating: bool,
+    #[serde(rename = "is-sticky", default)]
     is_sticky: bool,
-    #[serde(rename = "is-topmost")]
+    #[serde(rename = "is-topmost", default)]
     is_topmost: bool,
-    #[serde(rename = "is-grabbed")]
+    #[serde(rename = "is-grabbed", default)]
     is_grabbed: bool,
 }
 
+fn can_move_default() -> bool {
+    true
+}
+
+impl Window {
+    pub fn key(&self) -> WindowKey {
+        WindowKey {
+            app: self.app.clone(),
+            title: self.title.clone(),
+        }
+    }
+}
+
+// Sorted by the centers of the frames, see Frame::is_left_of(). Displays
+// at the same x keep yabai's order.
+pub fn displays_left_to_right(displays: &[Display]) -> Vec<u32> {
+    let mut displays: Vec<&Display> = displays.iter().collect();
+    displays.sort_by(|a, b| a.frame.center().0.total_cmp(&b.frame.center().0));
+    displays.iter().map(|display| display.index).collect()
+}
+
+pub fn assume_displays(num_displays: u32) {
+    ASSUMED_DISPLAYS.store(num_displays, Ordering::Relaxed);
+}
+
 fn save<T>(states: &T, filename: &str) -> Result<()>
 where
     T: Serialize,
 {
-    let file = File::create(get_full_path(filename)?)?;
-    let result = serde_json::to_writer(file, states)?;
-    Ok(result)
+    if yabai::is_dry_run() {
+        return Ok(());
+    }
+    let file = File::create(get_writable_path(filename)?)?;
+    serde_json::to_writer(file, states)?;
+    Ok(())
 }
 
 fn load<T>(filename: &str) -> Result<T>
@@ -183,23 +608,151 @@ where
     Ok(json)
 }
 
+// Held while a coalesced command runs. Dropping it releases the lock.
+pub struct Coalesced {
+    _lock: Option<File>,
+}
+
+// Run only the latest of a burst of invocations, e.g. focus-space next with
+// a key held down. Every invocation leaves its ticket, then waits for the
+// lock. Whoever finds a ticket other than its own once it gets the lock has
+// been superseded and gets None back.
+pub fn coalesce(name: &str) -> Result<Option<Coalesced>> {
+    if yabai::is_dry_run() {
+        return Ok(Some(Coalesced { _lock: None }));
+    }
+    let ticket_path = get_writable_path(&format!("{}.{}.ticket", YABAICTL_STATE, name))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let ticket = format!("{}:{}", process::id(), now.as_nanos());
+    fs::write(&ticket_path, &ticket)?;
+
+    let lock = File::create(get_writable_path(&format!(
+        "{}.{}.lock",
+        YABAICTL_STATE, name
+    ))?)?;
+    lock.lock()?;
+    if fs::read_to_string(&ticket_path)? != ticket {
+        return Ok(None);
+    }
+    Ok(Some(Coalesced { _lock: Some(lock) }))
+}
+
 fn get_full_path(filename: &str) -> Result<PathBuf> {
-    let home = std::env::var("HOME")?;
-    let path = PathBuf::from(format!("{}/.cache/{}", home, filename));
+    Ok(cache_dir(std::env::var_os("XDG_CACHE_HOME"), std::env::var_os("HOME"))?.join(filename))
+}
+
+// XDG_CACHE_HOME, or ~/.cache when it is unset. The spec says to ignore it
+// when it is empty or not an absolute path.
+fn cache_dir(xdg_cache_home: Option<OsString>, home: Option<OsString>) -> Result<PathBuf> {
+    if let Some(dir) = xdg_cache_home.map(PathBuf::from) {
+        if dir.is_absolute() {
+            return Ok(dir);
+        }
+    }
+    let home = home.context("Neither XDG_CACHE_HOME nor HOME is set")?;
+    Ok(PathBuf::from(home).join(".cache"))
+}
+
+// A fresh or minimal system doesn't necessarily have its cache directory
+// yet.
+fn create_parent_dir(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+// get_full_path, making sure the directory exists before anything is
+// written there.
+fn get_writable_path(filename: &str) -> Result<PathBuf> {
+    let path = get_full_path(filename)?;
+    create_parent_dir(&path)?;
     Ok(path)
 }
 
+// While this file exists, commands don't restore on their own. See pause.
+fn paused_path() -> Result<PathBuf> {
+    get_full_path(&format!("{}.paused", YABAICTL_STATE))
+}
+
+pub fn is_paused() -> Result<bool> {
+    Ok(paused_path()?.exists())
+}
+
+pub fn set_paused(paused: bool) -> Result<()> {
+    if yabai::is_dry_run() {
+        return Ok(());
+    }
+    let path = paused_path()?;
+    if paused {
+        create_parent_dir(&path)?;
+        File::create(&path)?;
+    } else if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    Ok(())
+}
+
+// Delete a saved state. Returns false when there was none.
+fn clear(filename: &str) -> Result<bool> {
+    let path = get_full_path(filename)?;
+    if !path.exists() {
+        return Ok(false);
+    }
+    if yabai::is_dry_run() {
+        println!("rm {}", path.display());
+        return Ok(true);
+    }
+    fs::remove_file(&path).with_context(|| format!("Failed to delete {}", path.display()))?;
+    Ok(true)
+}
+
+pub fn has_yabai() -> Result<bool> {
+    Ok(get_full_path(YABAI_STATE)?.exists())
+}
+
+pub fn has_yabaictl() -> Result<bool> {
+    Ok(get_full_path(YABAICTL_STATE)?.exists())
+}
+
+pub fn clear_yabai() -> Result<bool> {
+    clear(YABAI_STATE)
+}
+
+pub fn clear_yabaictl() -> Result<bool> {
+    clear(YABAICTL_STATE)
+}
+
 pub fn load_yabaictl() -> Result<YabaictlStates> {
     let states: YabaictlStates = load(YABAICTL_STATE)?;
     Ok(states)
 }
 
+pub fn load_yabaictl_or_default() -> Result<YabaictlStates> {
+    if !has_yabaictl()? {
+        return Ok(YabaictlStates::default());
+    }
+    load_yabaictl()
+}
+
 pub fn load_yabai() -> Result<YabaiStates> {
     let states: YabaiStates = load(YABAI_STATE)?;
     Ok(states)
 }
 
+// Nothing has been saved yet on the very first run.
+pub fn load_yabai_or_default() -> Result<YabaiStates> {
+    if !has_yabai()? {
+        return Ok(YabaiStates::default());
+    }
+    load_yabai()
+}
+
 pub fn save_yabai(states: &YabaiStates) -> Result<()> {
+    if states.partial {
+        return Ok(());
+    }
     save(states, YABAI_STATE)?;
     Ok(())
 }
@@ -208,3 +761,203 @@ pub fn save_yabaictl(states: &YabaictlStates) -> Result<()> {
     save(states, YABAICTL_STATE)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::fixture;
+
+    #[test]
+    fn frames_left_of_the_menu_bar() {
+        let states = fixture("two_displays");
+        let right = &states.displays[0].frame;
+        let left = &states.displays[1].frame;
+        assert_eq!(left.x, -1920.0);
+        assert_eq!(left.center(), (-960.0, 540.0));
+        assert!(left.is_left_of(right));
+        assert!(!right.is_left_of(left));
+        let above = Frame {
+            x: -200.0,
+            y: -1440.0,
+            w: 2560.0,
+            h: 1440.0,
+        };
+        assert_eq!(above.center(), (1080.0, -720.0));
+        assert!(left.is_left_of(&above));
+        assert!(!above.is_left_of(right));
+    }
+
+    #[test]
+    fn fixtures_survive_a_round_trip() {
+        // export-state writes what fixture() reads.
+        let states = fixture("two_displays");
+        let json = serde_json::to_string_pretty(&states).unwrap();
+        let again: YabaiStates = serde_json::from_str(&json).unwrap();
+        assert_eq!(again.num_spaces(), states.num_spaces());
+        assert_eq!(again.display_fingerprint(), states.display_fingerprint());
+        assert_eq!(serde_json::to_string_pretty(&again).unwrap(), json);
+    }
+
+    #[test]
+    fn empty_states_are_degenerate() {
+        let states = YabaiStates::default();
+        assert!(states.is_degenerate());
+        assert_eq!(states.num_spaces(), 0);
+        assert_eq!(states.num_displays(), 0);
+        assert!(states.focused_display_index().is_none());
+        assert!(!states.focused_display_unlabeled());
+    }
+
+    #[test]
+    fn states_without_focus_are_degenerate() {
+        let mut states = fixture("two_displays");
+        assert!(!states.is_degenerate());
+        for space in states.spaces.iter_mut() {
+            space.has_focus = false;
+        }
+        assert!(states.is_degenerate());
+    }
+
+    #[test]
+    fn states_without_displays_are_degenerate() {
+        let mut states = fixture("two_displays");
+        states.displays.clear();
+        states.spaces.clear();
+        assert!(states.is_degenerate());
+    }
+
+    #[test]
+    fn display_fingerprint_ignores_order() {
+        let mut states = fixture("two_displays");
+        let fingerprint = states.display_fingerprint();
+        assert_eq!(
+            fingerprint,
+            "LEFT-UUID@-1920,0,1920x1080;RIGHT-UUID@0,0,1920x1080"
+        );
+        states.displays.reverse();
+        assert_eq!(states.display_fingerprint(), fingerprint);
+        states.displays[0].frame.x = 1920.0;
+        assert_ne!(states.display_fingerprint(), fingerprint);
+    }
+
+    #[test]
+    fn prune_recent_drops_and_clamps() {
+        let mut states = fixture("two_displays");
+        // s9 and s10 were destroyed.
+        states
+            .spaces
+            .retain(|space| space.label != "s9" && space.label != "s10");
+        let mut ctl = YabaictlStates::default();
+        ctl.recent_by_display.insert("A".to_string(), 3);
+        ctl.recent_by_display.insert("B".to_string(), 10);
+        ctl.recent_by_display.insert("C".to_string(), 0);
+        assert!(ctl.prune_recent(&states));
+        assert_eq!(ctl.recent_by_display["A"], 3);
+        assert_eq!(ctl.recent_by_display["B"], 8);
+        assert_eq!(ctl.recent_by_display["C"], 0);
+        assert!(!ctl.prune_recent(&states));
+
+        states.spaces.clear();
+        assert!(ctl.prune_recent(&states));
+        assert_eq!(ctl.recent_by_display.len(), 1);
+    }
+
+    #[test]
+    fn problems_of_consistent_states() {
+        let states = fixture("two_displays");
+        assert!(states.problems().is_empty());
+        let ctl = YabaictlStates {
+            display_fingerprint: states.display_fingerprint(),
+            ..Default::default()
+        };
+        assert!(ctl.problems(&states).is_empty());
+    }
+
+    #[test]
+    fn problems_report_recent_out_of_range() {
+        let mut states = fixture("two_displays");
+        states.spaces.retain(|space| space.label != "s10");
+        let mut ctl = YabaictlStates {
+            display_fingerprint: states.display_fingerprint(),
+            ..Default::default()
+        };
+        ctl.recent_by_display.insert("RIGHT-UUID".to_string(), 10);
+        ctl.recent_by_display.insert("GONE-UUID".to_string(), 3);
+        ctl.recent_windows = vec![12345];
+        ctl.recent_window_cursor = 1;
+        let problems = ctl.problems(&states);
+        assert_eq!(problems.len(), 4);
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("s10") && p.contains("s9")));
+        assert!(problems.iter().any(|p| p.contains("GONE-UUID")));
+    }
+
+    #[test]
+    fn cold_after_a_reload() {
+        let mut states = fixture("two_displays");
+        assert!(!states.is_cold());
+        // The unfocused space 7 still holds window 301, but doesn't say.
+        for space in states.spaces.iter_mut().filter(|space| !space.has_focus) {
+            space.windows.clear();
+        }
+        assert!(states.is_cold());
+        states.windows.retain(|window| window.id != 301);
+        assert!(!states.is_cold());
+    }
+
+    #[test]
+    fn focused_display_from_focused_space() {
+        let states = fixture("two_displays");
+        assert_eq!(states.focused_display().unwrap().uuid, "RIGHT-UUID");
+    }
+
+    #[test]
+    fn focused_display_from_has_focus() {
+        let mut states = fixture("two_displays");
+        states.displays[1].has_focus = true;
+        assert_eq!(states.focused_display().unwrap().uuid, "LEFT-UUID");
+    }
+
+    #[test]
+    fn focused_display_index_without_display_states() {
+        let mut states = fixture("two_displays");
+        states.displays.clear();
+        assert!(states.focused_display().is_none());
+        assert_eq!(states.focused_display_index(), Some(1));
+    }
+
+    #[test]
+    fn focused_display_without_labeled_spaces() {
+        let states = fixture("focused_display_unlabeled");
+        assert!(states.focused_display_unlabeled());
+    }
+
+    #[test]
+    fn focused_display_with_labeled_spaces() {
+        let states = fixture("two_displays");
+        assert!(!states.focused_display_unlabeled());
+    }
+
+    #[test]
+    fn cache_dir_prefers_absolute_xdg() {
+        let home = Some(OsString::from("/Users/me"));
+        assert_eq!(
+            cache_dir(Some(OsString::from("/tmp/cache")), home.clone()).unwrap(),
+            PathBuf::from("/tmp/cache")
+        );
+        assert_eq!(
+            cache_dir(Some(OsString::from("cache")), home.clone()).unwrap(),
+            PathBuf::from("/Users/me/.cache")
+        );
+        assert_eq!(
+            cache_dir(Some(OsString::new()), home.clone()).unwrap(),
+            PathBuf::from("/Users/me/.cache")
+        );
+        assert_eq!(
+            cache_dir(None, home).unwrap(),
+            PathBuf::from("/Users/me/.cache")
+        );
+        assert!(cache_dir(None, None).is_err());
+    }
+}
@@ -1,19 +1,44 @@
 use anyhow::{bail, Context, Result};
-use byteorder::{LittleEndian, WriteBytesExt};
 use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs;
 use std::io::prelude::*;
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Mutex, OnceLock};
 use std::thread;
 use std::time::{Duration, Instant};
 use structopt::clap::arg_enum;
 
-use crate::states::{self, Display, Space, Window, YabaiStates, YabaictlStates};
+use crate::config::{self, EdgeMode, FallbackMode, LabelsOnly, MissingSpace, PrimarySide, Spacing};
+use crate::states::{self, Display, Space, Window, WindowKey, YabaiStates};
 
-pub const NUM_SPACES: u32 = 10;
+// The yabai versions yabaictl has been tried with, by major and minor. The
+// query fields and the error strings it matches on change between them.
+pub const TESTED_YABAI_VERSIONS: ((u32, u32), (u32, u32)) = ((4, 0), (7, 1));
 const YABAI_FAILURE_BYTE: u8 = 0x07;
 
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+// The messages sent to yabai so far, or printed instead in dry-run. See
+// bench-restore.
+static YABAI_CALLS: AtomicU32 = AtomicU32::new(0);
+// Query responses that the next identical query may take instead of asking
+// yabai again. None unless enabled, see enable_query_cache().
+static QUERY_CACHE: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+static LABELS_ONLY: OnceLock<bool> = OnceLock::new();
+
+// How many spaces make up the composite desktops of a pair of displays,
+// e.g. 10 for 5 desktops. Set by num_spaces in the config or
+// YABAICTL_NUM_SPACES.
+pub fn num_spaces() -> u32 {
+    config::get().num_spaces
+}
+
 arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq)]
     pub enum WindowArg {
@@ -52,6 +77,41 @@ impl WindowOp {
     }
 }
 
+arg_enum! {
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum CompositeOp {
+        Float,
+        Unfloat,
+        Opacity,
+        Minimize,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum InsertArg {
+        First,
+        Last,
+        Largest,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum SideArg {
+        Left,
+        Right,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum StateArg {
+        Yabai,
+        Yabaictl,
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum SpaceArg {
     Next,
@@ -62,6 +122,15 @@ pub enum SpaceArg {
     Space(u32),
 }
 
+#[derive(Debug, Default)]
+pub struct RestoreOptions {
+    // Don't cycle focus through every space, and put focus back where it
+    // was once done.
+    pub keep_focus: bool,
+    // Focus the desktop that was focused before, by label, once done.
+    pub keep_desktop: bool,
+}
+
 #[derive(Debug)]
 pub enum QueryDomain {
     Windows,
@@ -79,16 +148,199 @@ impl QueryDomain {
     }
 }
 
-pub fn yabai_message(msgs: &[&str]) -> Result<String> {
-    let mut command = String::new();
+// Without the scripting addition yabai can't create, destroy or move
+// spaces. In that case restore only labels the spaces the user has set up,
+// display by display, and leaves the rest alone.
+pub fn is_labels_only() -> bool {
+    *LABELS_ONLY.get_or_init(|| match config::get().labels_only {
+        LabelsOnly::Always => true,
+        LabelsOnly::Never => false,
+        LabelsOnly::Auto => sip_enabled(),
+    })
+}
+
+fn sip_enabled() -> bool {
+    // A partially disabled SIP reports a custom configuration, which is
+    // what the scripting addition asks for.
+    match Command::new("csrutil").arg("status").output() {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).contains("status: enabled."),
+        Err(_) => false,
+    }
+}
+
+// yabai's wire format: the payload length as a little-endian u32, followed
+// by every argument terminated by a null byte, and one more null byte at the
+// end.
+fn encode_message(msgs: &[&str]) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
     for msg in msgs.iter() {
-        command.push_str(msg);
-        command.push('\0');
+        payload.extend_from_slice(msg.as_bytes());
+        payload.push(0);
+    }
+    payload.push(0);
+
+    let len: u32 = payload
+        .len()
+        .try_into()
+        .context("Message too long for yabai")?;
+    let mut message = len.to_le_bytes().to_vec();
+    message.append(&mut payload);
+    Ok(message)
+}
+
+fn write_message<W: Write>(stream: &mut W, message: &[u8]) -> Result<()> {
+    let mut written = 0;
+    while written < message.len() {
+        match stream.write(&message[written..]) {
+            Ok(0) => bail!(
+                "yabai closed the socket after {} of {} bytes",
+                written,
+                message.len()
+            ),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+// Where the yabai socket could be, in order: YABAI_SOCKET, which yabai
+// exports itself, the socket from the config, then yabai's default for the
+// user. launchd agents don't necessarily have USER.
+fn socket_candidates(
+    env_socket: Option<OsString>,
+    config_socket: Option<&str>,
+    user: Option<OsString>,
+) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    candidates.extend(
+        env_socket
+            .filter(|socket| !socket.is_empty())
+            .map(PathBuf::from),
+    );
+    candidates.extend(config_socket.map(PathBuf::from));
+    if let Some(user) = user.and_then(|user| user.into_string().ok()) {
+        candidates.push(PathBuf::from(format!("/tmp/yabai_{}.socket", user)));
+    }
+    candidates
+}
+
+fn env_socket_candidates() -> Vec<PathBuf> {
+    socket_candidates(
+        std::env::var_os("YABAI_SOCKET"),
+        config::get().socket.as_deref(),
+        std::env::var_os("USER"),
+    )
+}
+
+fn socket_path() -> Result<PathBuf> {
+    let candidates = env_socket_candidates();
+    if candidates.is_empty() {
+        bail!("Can't find the yabai socket: YABAI_SOCKET, socket in the config and USER are all unset");
+    }
+    let found = candidates
+        .iter()
+        .find(|path| fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()));
+    match found {
+        Some(path) => Ok(path.clone()),
+        None => {
+            let tried: Vec<String> = candidates
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            bail!(
+                "No yabai socket at {}. Is yabai running?",
+                tried.join(" or ")
+            )
+        }
+    }
+}
+
+pub fn print_socket() -> Result<()> {
+    let candidates = env_socket_candidates();
+    if candidates.is_empty() {
+        println!("No socket to try: YABAI_SOCKET, socket in the config and USER are all unset");
+    }
+    for path in candidates.iter() {
+        match UnixStream::connect(path.as_path()) {
+            Ok(_) => println!("{} (connectable)", path.display()),
+            Err(e) => println!("{} (not connectable: {})", path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+// A burst of retries means yabai is struggling. Say so once per command.
+fn warn_on_retries(msgs: &[&str], retries: u32) {
+    if retries == config::get().retry_warn_threshold {
+        warn!(
+            "{:?} retried {} times, yabai seems to be struggling",
+            msgs, retries
+        );
+    }
+}
+
+// For batch: every command ends on a query and the next one starts on the
+// same query with nothing sent in between. Each response is good for one
+// more query at most, so polling loops still see yabai change, and anything
+// but a query throws them all away.
+pub fn enable_query_cache() {
+    *QUERY_CACHE.lock().unwrap() = Some(HashMap::new());
+}
+
+// yabai sometimes stalls for seconds, e.g. around display changes. Point
+// at the command that did.
+fn warn_if_slow(msgs: &[&str], duration: Duration) {
+    let threshold = config::get().slow_call_ms;
+    if threshold > 0 && duration > Duration::from_millis(threshold) {
+        warn!(
+            "{:?} took {:?}, over the {}ms slow_call_ms",
+            msgs, duration, threshold
+        );
+    }
+}
+
+pub fn yabai_message(msgs: &[&str]) -> Result<String> {
+    // Queries, and reading a config setting, are always safe to send.
+    // Everything else changes something.
+    let read_only = matches!(msgs, ["query", ..] | ["config", _]);
+    let key = msgs.join(" ");
+    if let Some(cache) = QUERY_CACHE.lock().unwrap().as_mut() {
+        if !read_only {
+            cache.clear();
+        } else if let Some(response) = cache.remove(&key) {
+            return Ok(response);
+        }
+    }
+    YABAI_CALLS.fetch_add(1, Ordering::Relaxed);
+    if is_dry_run() && !read_only {
+        println!("yabai -m {}", key);
+        return Ok("".to_string());
+    }
+    let response = send_message(msgs)?;
+    if read_only && !response.is_empty() {
+        if let Some(cache) = QUERY_CACHE.lock().unwrap().as_mut() {
+            cache.insert(key, response.clone());
+        }
     }
-    command.push('\0');
+    Ok(response)
+}
 
-    let user = std::env::var("USER")?;
-    let path = PathBuf::from(format!("/tmp/yabai_{}.socket", user));
+// yabai answers one message per connection, so there is no reusing the
+// socket.
+fn send_message(msgs: &[&str]) -> Result<String> {
+    let message = encode_message(msgs)?;
+    let path = socket_path()?;
+    let mut retries = 0;
 
     loop {
         let start = Instant::now();
@@ -99,8 +351,7 @@ pub fn yabai_message(msgs: &[&str]) -> Result<String> {
         stream.set_read_timeout(Some(Duration::new(10, 0)))?;
         stream.set_write_timeout(Some(Duration::new(10, 0)))?;
 
-        stream.write_u32::<LittleEndian>(command.len().try_into().unwrap())?;
-        stream.write_all(command.as_bytes())?;
+        write_message(&mut stream, &message)?;
 
         let mut buffer = Vec::new();
         let read = match stream.read_to_end(&mut buffer) {
@@ -112,7 +363,9 @@ pub fn yabai_message(msgs: &[&str]) -> Result<String> {
                         // Retry on this error:
                         //
                         //   Error: Resource temporarily unavailable (os error 35)
-                        eprintln!("{:?} {:?} got {:?}, retrying", msgs, duration, e);
+                        debug!("{:?} {:?} got {:?}, retrying", msgs, duration, e);
+                        retries += 1;
+                        warn_on_retries(msgs, retries);
                         continue;
                     }
                     _ => {
@@ -122,7 +375,8 @@ pub fn yabai_message(msgs: &[&str]) -> Result<String> {
             }
         };
         let duration = start.elapsed();
-        eprintln!("{:?} {:?}", msgs, duration);
+        debug!("{:?} {:?}", msgs, duration);
+        warn_if_slow(msgs, duration);
 
         if read == 0 {
             return Ok("".to_string());
@@ -140,15 +394,18 @@ where
     T: DeserializeOwned,
 {
     let command = &["query", param.as_str()];
+    let mut retries = 0;
     loop {
         let raw = yabai_message(command)?;
-        if raw == "" {
+        if raw.is_empty() {
             // Retry the query if yabai returns an empty string.
             //
             // We might be sending commands too fast to yabai. It
             // might not be able to handle the rapid fire series
             // of commands straight into the unix socket.
-            eprintln!("{:?} returned an empty string, retrying", command);
+            debug!("{:?} returned an empty string, retrying", command);
+            retries += 1;
+            warn_on_retries(command, retries);
             continue;
         }
         let json: T = serde_json::from_str(&raw)
@@ -157,46 +414,179 @@ where
     }
 }
 
+// Deserialization failures are not fatal for the non-essential domains. A
+// newer yabai may have renamed a field we don't strictly need.
+fn lenient<T>(r: Result<Vec<T>>, what: &str) -> Result<Option<Vec<T>>> {
+    match r {
+        Ok(states) => Ok(Some(states)),
+        Err(e) if e.downcast_ref::<serde_json::Error>().is_some() => {
+            warn!(
+                "Ignoring the {} states, failed to deserialize: {}",
+                what,
+                e.root_cause()
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e.context(format!("Failed to query yabai for the {} states", what))),
+    }
+}
+
 pub fn query() -> Result<YabaiStates> {
-    let windows: Vec<Window> =
-        yabai_query(QueryDomain::Windows).context("Failed to query yabai for the window states")?;
-    let displays: Vec<Display> = yabai_query(QueryDomain::Displays)
-        .context("Failed to query yabai for the display states")?;
+    let windows = lenient(yabai_query(QueryDomain::Windows), "window")?;
+    let displays = lenient(yabai_query(QueryDomain::Displays), "display")?;
     let spaces: Vec<Space> =
         yabai_query(QueryDomain::Spaces).context("Failed to query yabai for the space states")?;
+    let partial = windows.is_none() || displays.is_none();
+    let displays: Vec<Display> = displays.unwrap_or_default();
     let states = YabaiStates {
-        windows,
+        partial,
+        pinned_display: find_pinned_display(&displays),
+        sides_swapped: sides_swapped(
+            config::get().primary_display.as_deref(),
+            config::get().primary_side,
+            &displays,
+        ),
+        reserved_display: find_reserved_display(&displays),
+        windows: windows.unwrap_or_default(),
         displays,
         spaces,
     };
     Ok(states)
 }
 
+// query(), but waits out the empty snapshots yabai reports while it is
+// starting up, instead of doing arithmetic on them.
+fn query_settled() -> Result<YabaiStates> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let states = query()?;
+        if !states.is_degenerate() {
+            return Ok(states);
+        }
+        if Instant::now() > deadline {
+            bail!(
+                "yabai reports {} spaces and {} displays with no focus",
+                states.num_spaces(),
+                states.num_displays()
+            );
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+fn find_pinned_display(displays: &[Display]) -> Option<u32> {
+    let pinned = config::get().pinned_display.as_ref()?;
+    // Alone, the pinned display is just the one display. Labels-only mode
+    // can't move spaces onto it anyway.
+    if displays.len() < 2 || is_labels_only() {
+        return None;
+    }
+    displays
+        .iter()
+        .find(|display| match pinned.display.as_str() {
+            "built-in" => is_builtin_display(display.id),
+            uuid => display.uuid == uuid,
+        })
+        .map(|display| display.index)
+}
+
+// Where the reserved space goes instead of display 1. Labels-only mode
+// can't move it.
+fn find_reserved_display(displays: &[Display]) -> Option<u32> {
+    let reserved = config::get().reserved_display.as_deref()?;
+    if displays.len() < 2 || is_labels_only() {
+        return None;
+    }
+    displays
+        .iter()
+        .find(|display| match reserved {
+            "built-in" => is_builtin_display(display.id),
+            "external" => !is_builtin_display(display.id),
+            uuid => display.uuid == uuid,
+        })
+        .map(|display| display.index)
+}
+
+// Whether yabai's display 2 holds the even labels, the right half of every
+// composite desktop, instead of the display with the menu bar. That's up to
+// primary_display, then primary_side, which needs no frames either way.
+// With neither, the even labels go on whichever display is physically on
+// the right, however yabai numbers them.
+fn sides_swapped(
+    primary_display: Option<&str>,
+    primary_side: Option<PrimarySide>,
+    displays: &[Display],
+) -> bool {
+    match (primary_display, primary_side) {
+        (Some(primary), _) => primary_is_second(Some(primary), displays),
+        (None, Some(PrimarySide::Right)) => false,
+        (None, Some(PrimarySide::Left)) => displays.iter().any(|display| display.index == 2),
+        (None, None) => primary_is_second(Some("rightmost"), displays),
+    }
+}
+
+fn primary_is_second(primary: Option<&str>, displays: &[Display]) -> bool {
+    let primary = match primary {
+        None | Some("menu_bar") => return false,
+        Some(primary) => primary,
+    };
+    let find = |index: u32| displays.iter().find(|display| display.index == index);
+    let (first, second) = match (find(1), find(2)) {
+        (Some(first), Some(second)) => (first, second),
+        _ => return false,
+    };
+    // Displays without frames, or right on top of each other, have no left
+    // and right. Leave them as yabai numbers them.
+    let side_by_side =
+        first.frame.is_left_of(&second.frame) || second.frame.is_left_of(&first.frame);
+    let left_to_right: Vec<u32> = states::displays_left_to_right(displays)
+        .into_iter()
+        .filter(|index| *index <= 2)
+        .collect();
+    match primary {
+        "rightmost" => side_by_side && left_to_right == [1, 2],
+        "leftmost" => side_by_side && left_to_right == [2, 1],
+        uuid => second.uuid == uuid,
+    }
+}
+
+// yabai's display id is the CoreGraphics display id.
+#[cfg(target_os = "macos")]
+fn is_builtin_display(display_id: u32) -> bool {
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGDisplayIsBuiltin(display: u32) -> u32;
+    }
+    unsafe { CGDisplayIsBuiltin(display_id) != 0 }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn is_builtin_display(_display_id: u32) -> bool {
+    false
+}
+
 fn label_space(space_index: u32, label: &str) -> Result<()> {
     yabai_message(&["space", &space_index.to_string(), "--label", label])?;
     Ok(())
 }
 
 fn move_window_to_space(window_id: &u32, space: &str) -> Result<()> {
-    if space == "" {
-        eprintln!("Not moving {} to an unlabeled space", window_id);
+    if space.is_empty() {
+        info!("Not moving {} to an unlabeled space", window_id);
         return Ok(());
     }
     let r = yabai_message(&["window", &window_id.to_string(), "--space", space]);
-    match r {
-        Err(e) => {
-            if !e
+    if let Err(e) = r {
+        if !e
+            .to_string()
+            .contains("could not locate the window to act on!")
+            && !e
                 .to_string()
-                .contains(&"could not locate the window to act on!")
-                && !e
-                    .to_string()
-                    .contains(&"is not a valid option for SPACE_SEL")
-            {
-                return Err(e);
-            }
-            eprintln!("Not moving {}. It no longer exists", window_id);
+                .contains("is not a valid option for SPACE_SEL")
+        {
+            return Err(e);
         }
-        Ok(_) => {}
+        info!("Not moving {}. It no longer exists", window_id);
     }
     Ok(())
 }
@@ -206,367 +596,2885 @@ fn focus(space: &Space) -> Result<()> {
     Ok(())
 }
 
+// Label index 0 stands for the reserved space, e.g. when it was recorded as
+// the recent space. Nobody should land there, so go to s1 instead.
+fn avoid_reserved(label_index: u32) -> u32 {
+    if label_index == 0 {
+        info!("Not focusing the reserved space, focusing s1 instead");
+        return 1;
+    }
+    label_index
+}
+
 fn focus_space_by_label(label_index: u32) -> Result<()> {
+    let label_index = avoid_reserved(label_index);
     focus_space_arg(&format!("s{}", label_index))?;
     Ok(())
 }
 
+// Whether yabai moves the mouse along with focus, asked for only once.
+fn mouse_follows_focus() -> Result<bool> {
+    static MOUSE_FOLLOWS_FOCUS: OnceLock<bool> = OnceLock::new();
+    if let Some(&on) = MOUSE_FOLLOWS_FOCUS.get() {
+        return Ok(on);
+    }
+    let on = yabai_message(&["config", "mouse_follows_focus"])?.trim() == "on";
+    Ok(*MOUSE_FOLLOWS_FOCUS.get_or_init(|| on))
+}
+
+// Focus the half of a composite desktop that is only brought into view on
+// the way to the other half. With hold_mouse, yabai's mouse_follows_focus
+// is off for the time being, so the mouse goes straight to the space
+// focused last instead of over to this one first.
+fn focus_partner(label_index: u32) -> Result<()> {
+    let hold = config::get().hold_mouse && mouse_follows_focus()?;
+    if hold {
+        yabai_message(&["config", "mouse_follows_focus", "off"])?;
+    }
+    let r = focus_space_by_label(label_index);
+    if hold {
+        yabai_message(&["config", "mouse_follows_focus", "on"])?;
+    }
+    r
+}
+
 fn focus_space_arg(arg: &str) -> Result<()> {
     let r = yabai_message(&["space", "--focus", arg]);
-    match r {
-        Err(e) => {
-            if !e
-                .to_string()
-                .contains(&"cannot focus an already focused space.")
-            {
-                return Err(e);
-            }
+    if let Err(e) = r {
+        if !e
+            .to_string()
+            .contains("cannot focus an already focused space.")
+        {
+            return Err(e);
         }
-        Ok(_) => {}
     }
     Ok(())
 }
 
-fn move_space_to_display(space_index: u32, display_index: u32) -> Result<()> {
-    let r = yabai_message(&[
-        "space",
-        &space_index.to_string(),
-        "--display",
-        &display_index.to_string(),
-    ]);
+fn move_space_to_display(space: &str, display_index: u32) -> Result<()> {
+    let r = yabai_message(&["space", space, "--display", &display_index.to_string()]);
 
-    match r {
-        Err(e) => {
-            if !e
-                .to_string()
-                .contains(&"acting space is already located on the given display.")
-            {
-                return Err(e);
-            }
+    if let Err(e) = r {
+        if !e
+            .to_string()
+            .contains("acting space is already located on the given display.")
+        {
+            return Err(e);
         }
-        Ok(_) => {}
     }
     Ok(())
 }
 
+// s1 and s2 form a composite desktop, so are s3 and s4, and so on. See
+// ensure_labels() for the full arrangement.
+fn partner_label_index(label_index: u32) -> u32 {
+    if label_index.is_multiple_of(2) {
+        label_index - 1
+    } else {
+        label_index + 1
+    }
+}
+
 fn neighbor_space(states: &YabaiStates, direction: WindowArg) -> Option<&Space> {
     let focused_space = states.focused_space().expect("No focused space found");
-    let label_index = focused_space.label_index().expect("Invalid space label");
+    // Nothing is next to a fullscreen app's space.
+    let label_index = focused_space.label_index()?;
 
-    // My main window is on the right
     let next_label_index = match direction {
-        WindowArg::East => {
-            if label_index % 2 == 0 {
-                label_index - 1
-            } else {
-                label_index + 1
-            }
-        }
-        WindowArg::West => {
-            if label_index % 2 == 0 {
-                label_index - 1
-            } else {
-                label_index + 1
-            }
-        }
+        WindowArg::East | WindowArg::West => partner_label_index(label_index),
         _ => {
             return None;
         }
     };
+    let next_space = states.find_space_by_label_index(next_label_index)?;
 
-    states.find_space_by_label_index(next_label_index)
+    // The other half is only a neighbor in the direction its display
+    // actually is, going by the frames. Displays that report no frames, or
+    // sit on top of each other, are taken to be neighbors both ways.
+    let frame = |display: u32| {
+        states
+            .displays
+            .iter()
+            .find(|d| d.index == display)
+            .map(|d| d.frame.center().0)
+    };
+    if let (Some(here), Some(there)) = (frame(focused_space.display), frame(next_space.display)) {
+        let wrong_way = match direction {
+            WindowArg::East => there < here,
+            _ => there > here,
+        };
+        if wrong_way {
+            return None;
+        }
+    }
+    Some(next_space)
 }
 
-fn even_spaces(states: &YabaiStates) -> Result<()> {
-    // Evenly split the spaces among the monitors
-    match states.num_displays() {
-        1 => {}
-        _ => {
-            for i in 1..=NUM_SPACES {
-                if i <= NUM_SPACES / 2 {
-                    move_space_to_display(i + 1, 1)?
-                } else {
-                    move_space_to_display(i + 1, 2)?
-                }
-            }
-            for i in 3..=states.num_displays() {
-                move_space_to_display(NUM_SPACES + i - 1, i)?
-            }
+// The label on the other half of the composite desktop, for the labels of
+// the first two displays.
+fn swap_side(label: &str, display: u32) -> String {
+    match label
+        .strip_prefix('s')
+        .and_then(|index| index.parse::<u32>().ok())
+    {
+        Some(label_index) if display <= 2 && label_index <= num_spaces() => {
+            format!("s{}", partner_label_index(label_index))
         }
+        _ => label.to_string(),
     }
-    Ok(())
 }
 
-fn ensure_spaces(states: &YabaiStates) -> Result<YabaiStates> {
-    // Cycle through all the spaces and focus each one with a short delay.
-    // This gives yabai enough time to pick up the most up-to-date states.
-    // This is esp. important when yabai has just been reloaded, in which
-    // case the windows array in every space is empty (except for the one
-    // already in focus).
-    let focused_space = states.focused_space().expect("No focused space");
-    let sleep = Duration::from_millis(250);
-    for space in states.spaces.iter() {
-        focus(space)?;
-        thread::sleep(sleep);
-        yabai_message(&["space", "--layout", "bsp"])?;
+// How the displays beyond the first two are laid out, as (pairs, singles).
+// Each pair gets its own num_spaces composite spaces, each single display
+// gets one space. With an odd number of extra displays under mirror, the
+// last one is a single. The reserved space stays with the first two
+// displays, the pairs don't get one of their own.
+fn extra_displays(num_displays: u32, mode: FallbackMode) -> Result<(u32, u32)> {
+    let extra = num_displays.saturating_sub(2);
+    match mode {
+        FallbackMode::Bail => {
+            if extra > 0 {
+                bail!("Don't know how to handle {} monitors", num_displays);
+            }
+            Ok((0, 0))
+        }
+        FallbackMode::IgnoreExtra => Ok((0, extra)),
+        FallbackMode::Mirror => Ok((extra / 2, extra % 2)),
     }
-    focus(focused_space)?;
+}
 
-    let states = query()?;
+fn target_spaces(num_displays: u32, mode: FallbackMode) -> Result<u32> {
     // Add one for the unused Desktop 1. See comments in ensure_labels() for
     // more details.
-    //
-    // Display 3 and beyond have one desktop each.
-    let extra_spaces = if states.num_displays() > 2 {
-        states.num_displays() - 2
-    } else {
-        0
-    };
-    let target = NUM_SPACES + 1 + extra_spaces;
+    let (pairs, singles) = extra_displays(num_displays, mode)?;
+    Ok(num_spaces() + 1 + pairs * num_spaces() + singles)
+}
 
-    // Evenly distribute the spaces among displays to handle the edge
-    // case where only one space is left to destroy (and that would fail).
-    even_spaces(&states)?;
-    if states.num_spaces() < target {
-        for _i in states.num_spaces()..NUM_SPACES + 1 {
-            yabai_message(&["space", "--create"])?;
+// The display the space at the given yabai index belongs on.
+fn space_display(space_index: u32, num_displays: u32, mode: FallbackMode) -> Result<u32> {
+    if num_displays == 1 || space_index <= num_spaces() / 2 + 1 {
+        // Desktop 1 (reserved) and the first half go on display 1.
+        return Ok(1);
+    }
+    if space_index <= num_spaces() + 1 {
+        return Ok(2);
+    }
+    let (pairs, singles) = extra_displays(num_displays, mode)?;
+    let i = space_index - num_spaces() - 2;
+    if i < pairs * num_spaces() {
+        let pair = i / num_spaces();
+        let half = if i % num_spaces() < num_spaces() / 2 {
+            0
+        } else {
+            1
+        };
+        return Ok(3 + pair * 2 + half);
+    }
+    let i = i - pairs * num_spaces();
+    if i < singles {
+        return Ok(3 + pairs * 2 + i);
+    }
+    // Spare spaces kept by min_extra_spaces pile up on the last display,
+    // after everything else, so they never shift the indices of the spaces
+    // above.
+    Ok(num_displays)
+}
+
+// What restore wants every space to be, by yabai index: its label and the
+// display it belongs on.
+struct SpacePlan {
+    spaces: Vec<(String, u32)>,
+    num_displays: u32,
+    mode: FallbackMode,
+    pinned: bool,
+}
+
+impl SpacePlan {
+    fn new(
+        num_displays: u32,
+        pinned: Option<(u32, &[String])>,
+        mode: FallbackMode,
+    ) -> Result<SpacePlan> {
+        let (pinned_display, pinned_labels) = match pinned {
+            None => {
+                let spaces = (1..=target_spaces(num_displays, mode)?)
+                    .map(|space_index| {
+                        Ok((
+                            space_label(space_index, num_displays, mode)?,
+                            space_display(space_index, num_displays, mode)?,
+                        ))
+                    })
+                    .collect::<Result<_>>()?;
+                return Ok(SpacePlan {
+                    spaces,
+                    num_displays,
+                    mode,
+                    pinned: false,
+                });
+            }
+            Some(pinned) => pinned,
+        };
+        // Lay the other displays out as if the pinned one wasn't there, then
+        // slot the pinned display's spaces in. yabai numbers the spaces
+        // display by display, so they go wherever its index says. Desktop 1
+        // is always on display 1, so a pinned display 1 also takes the
+        // reserved space.
+        let composite_displays = num_displays - 1;
+        let mut by_display = vec![Vec::new(); num_displays as usize];
+        for space_index in 1..=target_spaces(composite_displays, mode)? {
+            let label = space_label(space_index, composite_displays, mode)?;
+            if pinned_display == 1 && label == "reserved" {
+                continue;
+            }
+            let display = space_display(space_index, composite_displays, mode)?;
+            let display = if display >= pinned_display {
+                display + 1
+            } else {
+                display
+            };
+            by_display[display as usize - 1].push(label);
         }
-    } else if states.num_spaces() > target {
-        for _i in target + 1..=states.num_spaces() {
-            yabai_message(&["space", &(target + 1).to_string(), "--destroy"])?;
+        let pinned_spaces = &mut by_display[pinned_display as usize - 1];
+        if pinned_display == 1 {
+            pinned_spaces.push("reserved".to_string());
         }
+        pinned_spaces.extend(pinned_labels.iter().cloned());
+
+        let spaces = by_display
+            .into_iter()
+            .zip(1..)
+            .flat_map(|(labels, display)| labels.into_iter().map(move |label| (label, display)))
+            .collect();
+        Ok(SpacePlan {
+            spaces,
+            num_displays,
+            mode,
+            pinned: true,
+        })
     }
-    // Now evenly distribute the spaces again after the creation/destruction.
-    even_spaces(&states)?;
 
-    Ok(query()?)
-}
+    fn for_states(states: &YabaiStates) -> Result<SpacePlan> {
+        let pinned = match (states.pinned_display, &config::get().pinned_display) {
+            (Some(display), Some(pinned)) => Some((display, pinned.labels.as_slice())),
+            _ => None,
+        };
+        let mut plan = SpacePlan::new(states.num_displays(), pinned, config::get().fallback_mode)?;
+        if states.sides_swapped && pinned.is_none() {
+            plan.swap_sides();
+        }
+        if let Some(display) = states.reserved_display {
+            plan.move_reserved(display);
+        }
+        Ok(plan)
+    }
 
-fn ensure_labels(states: &YabaiStates) -> Result<YabaiStates> {
-    // Desktop 1 is reserved. We don't put anything there because of this apple
-    // issue:
-    //
-    // https://github.com/koekeishiya/yabai/discussions/238#discussioncomment-193399
-    label_space(1, "reserved")?;
+    // Put the reserved space first on the given display instead of display
+    // 1. The other spaces keep their displays, so whatever follows the
+    // reserved space in yabai's order shifts by one.
+    fn move_reserved(&mut self, display: u32) {
+        let reserved = match self
+            .spaces
+            .iter()
+            .position(|(label, _)| label == "reserved")
+        {
+            Some(reserved) => reserved,
+            None => return,
+        };
+        self.spaces.remove(reserved);
+        let position = self
+            .spaces
+            .iter()
+            .position(|(_, other)| *other >= display)
+            .unwrap_or(self.spaces.len());
+        self.spaces
+            .insert(position, ("reserved".to_string(), display));
+    }
 
-    match states.num_displays() {
-        1 => {
-            // One monitor is easy. Just label Desktop 2 as s1, D3 as s2, D4 as
-            // s3, and so on. (Again, as mentioned above, we leave Desktop 1
-            // unused to get around a quirk in MacOS).
-            for i in 1..states.num_spaces() {
-                label_space((i + 1).try_into()?, &format!("s{}", i))?;
-            }
+    // Trade the halves of the composite desktops of the first two displays,
+    // so display 1 holds the odd labels after the reserved space and display
+    // 2 the even ones.
+    fn swap_sides(&mut self) {
+        for (label, display) in self.spaces.iter_mut() {
+            *label = swap_side(label, *display);
         }
-        _ => {
-            // This is the arrangement for two monitors with the one on the
-            // right as primary:
-            //
-            // Right monitor:
-            //
-            // reserved s2 s4 s6 s8 s10 <= yabai space labels
-            // Desktop1 D2 D3 D4 D5 D6  <= MacOS Desktop
-            //
-            // Left monitor:
-            //
-            // s1 s3 s5 s7  s9
-            // D7 D8 D9 D10 D11
-            //
-            // With this arrangement, s1 and s2 form a single composite desktop,
-            // so are s3 and s4, s5 and s6, and so on.
-            //
-            // The `focus_space` subcommand would switch two monitors in unison
-            // as a single desktop.
-            for i in 1..states.num_spaces() {
-                if i <= NUM_SPACES / 2 {
-                    label_space((i + 1).try_into()?, &format!("s{}", i * 2))?;
-                } else if i <= NUM_SPACES {
-                    label_space(
-                        (i + 1).try_into()?,
-                        &format!("s{}", (i - NUM_SPACES / 2) * 2 - 1),
-                    )?;
-                } else {
-                    label_space((i + 1).try_into()?, &format!("s{}", NUM_SPACES + i - NUM_SPACES))?;
-                }
-            }
+    }
+
+    // The displays that take part in composite desktops: the first two, and
+    // the pairs after them under mirror. Not the pinned display, nor the
+    // singles.
+    fn composite_displays(&self) -> u32 {
+        let num_displays = if self.pinned {
+            self.num_displays - 1
+        } else {
+            self.num_displays
+        };
+        if num_displays < 2 {
+            return num_displays;
         }
+        let pairs = extra_displays(num_displays, self.mode).map_or(0, |(pairs, _)| pairs);
+        2 + pairs * 2
     }
-    Ok(query()?)
-}
 
-fn reorganize_spaces(states: &YabaiStates) -> Result<YabaiStates> {
-    let old_states = states::load_yabai()?;
+    fn target(&self) -> u32 {
+        self.spaces.len().try_into().unwrap()
+    }
 
-    for space in old_states.spaces.iter() {
-        for window_id in space.windows.iter() {
-            if space.label == "reserved" {
-                move_window_to_space(window_id, "s1")?;
-            } else {
-                if states
-                    .find_window_id_in_space(&space.label, window_id)
-                    .is_none()
-                {
-                    move_window_to_space(window_id, &space.label)?;
-                }
-            }
+    // Spare spaces kept by min_extra_spaces are not in the plan. They go
+    // wherever the scheme puts them, or on the last composite display with
+    // a pinned display.
+    fn label(&self, space_index: u32) -> Result<String> {
+        match self.spaces.get(space_index as usize - 1) {
+            Some((label, _)) => Ok(label.clone()),
+            None if self.pinned => Ok(format!("s{}", space_index - 1)),
+            None => space_label(space_index, self.num_displays, self.mode),
         }
     }
 
-    Ok(query()?)
-}
+    fn display(&self, space_index: u32) -> Result<u32> {
+        match self.spaces.get(space_index as usize - 1) {
+            Some((_, display)) => Ok(*display),
+            None if self.pinned => Ok(self.spaces.last().map_or(1, |(_, display)| *display)),
+            None => space_display(space_index, self.num_displays, self.mode),
+        }
+    }
 
-pub fn restore_spaces() -> Result<()> {
-    let states = query()?;
-    let states = restore_spaces_core(states)?;
-    states::save_yabai(&states)?;
-    Ok(())
+    fn labels(&self, num_spaces: u32) -> Result<Vec<(u32, String)>> {
+        (1..=num_spaces)
+            .map(|space_index| Ok((space_index, self.label(space_index)?)))
+            .collect()
+    }
 }
 
-fn restore_spaces_core(states: YabaiStates) -> Result<YabaiStates> {
-    let states = ensure_spaces(&states)?;
-    let states = ensure_labels(&states)?;
-    let states = reorganize_spaces(&states)?;
-    // Probably a yabai bug somehwere. When this is called by yabai on a signal
-    // of the display_added event, sending a window to a different space
-    // sometimes doesn't take effect. So, here we run it twice.
-    let states = reorganize_spaces(&states)?;
-    Ok(states)
+// The spaces not on the display the plan has for them, as (yabai index,
+// display).
+fn misplaced_spaces(states: &YabaiStates) -> Result<Vec<(u32, u32)>> {
+    let plan = SpacePlan::for_states(states)?;
+    let keep = plan.target() + config::get().min_extra_spaces;
+    let mut misplaced = Vec::new();
+    for space_index in 2..=states.num_spaces().min(keep) {
+        let display = plan.display(space_index)?;
+        let space = states
+            .spaces
+            .iter()
+            .find(|space| space.index == space_index);
+        if space.is_some_and(|space| space.display != display) {
+            misplaced.push((space_index, display));
+        }
+    }
+    Ok(misplaced)
 }
 
-fn restore_if_necessary(states: YabaiStates) -> Result<YabaiStates> {
-    if states.find_unlabeled_space().is_none() {
-        return Ok(states);
+fn move_spaces(states: &YabaiStates) -> Result<()> {
+    let plan = SpacePlan::for_states(states)?;
+    let keep = plan.target() + config::get().min_extra_spaces;
+    for space_index in 2..=states.num_spaces().min(keep) {
+        move_space_to_display(&space_index.to_string(), plan.display(space_index)?)?;
     }
-    eprintln!("Restoring spaces");
-    let states = restore_spaces_core(states)?;
-    Ok(states)
+    Ok(())
 }
 
-pub fn focus_space(space: SpaceArg) -> Result<()> {
-    let states = query()?;
-    let states = restore_if_necessary(states)?;
-
-    let focused_space = states.focused_space().expect("No focused space found");
-    let focused_label_index = focused_space.label_index().unwrap_or(0);
-    let display_count = if states.num_displays() >= 2 { 2 } else { 1 };
-    let label_index = match space {
-        SpaceArg::Recent => {
-            let ctl = states::load_yabaictl()?;
-            if ctl.recent > states.num_spaces() {
-                bail!(
-                    "recent space {} > number of spaces {}",
-                    ctl.recent,
-                    states.num_spaces()
-                )
-            }
-            ctl.recent
+fn even_spaces(states: &YabaiStates) -> Result<()> {
+    // Evenly split the spaces among the monitors
+    if states.num_displays() == 1 {
+        return Ok(());
+    }
+    debug!(
+        "Displays from left to right: {:?}, even labels on display {}",
+        states.displays_left_to_right(),
+        if states.sides_swapped { 2 } else { 1 }
+    );
+    move_spaces(states)?;
+    if is_dry_run() {
+        return Ok(());
+    }
+    // yabai sometimes reports success for a move that never happens, most
+    // often right after a display is added. Check where the spaces ended up
+    // and go again while some didn't make it. Every move shifts the indices
+    // of the spaces after it, so redo the whole pass on fresh states.
+    for _ in 0..3 {
+        let states = query()?;
+        let misplaced = misplaced_spaces(&states)?;
+        if misplaced.is_empty() {
+            return Ok(());
         }
-        SpaceArg::Next => {
-            let index = focused_label_index + display_count;
-            if index > NUM_SPACES {
-                index % NUM_SPACES
-            } else {
-                index
-            }
+        info!(
+            "Spaces on the wrong display after moving, retrying: {:?}",
+            misplaced
+        );
+        move_spaces(&states)?;
+    }
+    let misplaced = misplaced_spaces(&query()?)?;
+    if !misplaced.is_empty() {
+        warn!("Spaces still on the wrong display: {:?}", misplaced);
+    }
+    Ok(())
+}
+
+// Why a restore is needed, or None when the spaces are already in order:
+// the count is right, every space carries its label and sits on its display,
+// and the reserved space is empty.
+fn restore_needed(states: &YabaiStates) -> Result<Option<String>> {
+    if is_labels_only() {
+        return labels_needed(states);
+    }
+    let plan = SpacePlan::for_states(states)?;
+    let target = plan.target();
+    let keep = target + config::get().min_extra_spaces;
+    if states.num_spaces() < target || states.num_spaces() > keep {
+        return Ok(Some(format!(
+            "{} spaces, expected {}",
+            states.num_spaces(),
+            target
+        )));
+    }
+    for space in states.spaces.iter() {
+        let label = plan.label(space.index)?;
+        if space.label != label {
+            return Ok(Some(format!(
+                "space {} is labeled {:?}, expected {:?}",
+                space.index, space.label, label
+            )));
+        }
+        let display = plan.display(space.index)?;
+        if space.display != display {
+            return Ok(Some(format!(
+                "{} is on display {}, expected {}",
+                label, space.display, display
+            )));
+        }
+        if label == "reserved" && !space.windows.is_empty() {
+            return Ok(Some("the reserved space has windows".to_string()));
+        }
+    }
+    Ok(None)
+}
+
+// The layout to give every space: the configured one, or else yabai's own
+// default from the yabairc, asked for only once.
+fn space_layout() -> Result<&'static str> {
+    static YABAI_LAYOUT: OnceLock<String> = OnceLock::new();
+    if let Some(layout) = &config::get().layout {
+        return Ok(layout);
+    }
+    if let Some(layout) = YABAI_LAYOUT.get() {
+        return Ok(layout);
+    }
+    let layout = yabai_message(&["config", "layout"])?.trim().to_string();
+    let layout = if layout.is_empty() {
+        "bsp".to_string()
+    } else {
+        layout
+    };
+    Ok(YABAI_LAYOUT.get_or_init(|| layout))
+}
+
+// Set the layout, and the padding and gap when configured, of the given
+// space or the focused one.
+fn configure_space(space_index: Option<u32>, spacing: &Spacing) -> Result<()> {
+    let selector = space_index.map(|index| index.to_string());
+    let send = |args: &[&str]| -> Result<()> {
+        let mut msgs = vec!["space"];
+        if let Some(selector) = &selector {
+            msgs.push(selector);
+        }
+        msgs.extend_from_slice(args);
+        yabai_message(&msgs)?;
+        Ok(())
+    };
+    if config::get().apply_layout {
+        send(&["--layout", space_layout()?])?;
+    }
+    if let Some(padding) = spacing.padding() {
+        send(&["--padding", &padding])?;
+    }
+    if let Some(gap) = spacing.window_gap {
+        send(&["--gap", &format!("abs:{}", gap)])?;
+    }
+    Ok(())
+}
+
+// Cycle through all the spaces and focus each one with a short delay, then
+// run each on it. This gives yabai enough time to pick up the most
+// up-to-date states. This is esp. important when yabai has just been
+// reloaded, in which case the windows array in every space is empty (except
+// for the one already in focus).
+fn cycle_spaces<F>(states: &YabaiStates, mut each: F) -> Result<()>
+where
+    F: FnMut() -> Result<()>,
+{
+    let focused_space = states.focused_space().expect("No focused space");
+    let sleep = Duration::from_millis(250);
+    for space in states.spaces.iter() {
+        focus(space)?;
+        thread::sleep(sleep);
+        each()?;
+    }
+    focus(focused_space)?;
+    Ok(())
+}
+
+// query_settled(), plus a cycle through the spaces when the snapshot is
+// cold, for whatever goes by the windows arrays of the spaces.
+fn query_warm() -> Result<YabaiStates> {
+    let states = query_settled()?;
+    if !states.is_cold() {
+        return Ok(states);
+    }
+    info!("The windows of the spaces are not known yet, visiting every space");
+    cycle_spaces(&states, || Ok(()))?;
+    query()
+}
+
+fn ensure_spaces(states: &YabaiStates, options: &RestoreOptions) -> Result<YabaiStates> {
+    let spacing = config::get().spacing_for(states.num_displays());
+    if !config::get().apply_layout {
+        // Nothing to cycle through the spaces for. Only the spacing, if
+        // any, is left to set, through the space selector like below.
+        if spacing != Spacing::default() {
+            for space in states.spaces.iter() {
+                configure_space(Some(space.index), &spacing)?;
+            }
+        }
+    } else if options.keep_focus {
+        // Set the layout through the space selector instead. The windows
+        // arrays may stay stale after a reload, so reorganize_spaces() relies
+        // on Window.space.
+        for space in states.spaces.iter() {
+            configure_space(Some(space.index), &spacing)?;
+        }
+    } else {
+        cycle_spaces(states, || configure_space(None, &spacing))?;
+    }
+
+    let states = query()?;
+    if is_labels_only() {
+        return Ok(states);
+    }
+    fix_space_count(&states, false)
+}
+
+// Create or destroy spaces until there are as many as the scheme wants, and
+// spread them over the displays. With keep_windows, spaces that still hold
+// windows are not destroyed.
+fn fix_space_count(states: &YabaiStates, keep_windows: bool) -> Result<YabaiStates> {
+    let target = SpacePlan::for_states(states)?.target();
+    // Anything above target is spare. Only destroy what exceeds the
+    // configured allowance.
+    let keep = target + config::get().min_extra_spaces;
+
+    // Evenly distribute the spaces among displays to handle the edge
+    // case where only one space is left to destroy (and that would fail).
+    even_spaces(states)?;
+    adjust_space_count(states, target, keep, keep_windows)?;
+    // A create or destroy that gave up leaves the count off. Check and go
+    // again.
+    let mut states = query()?;
+    for _ in 1..SPACE_COUNT_ATTEMPTS {
+        if is_dry_run() || !adjust_space_count(&states, target, keep, keep_windows)? {
+            break;
+        }
+        states = query()?;
+    }
+    // Now evenly distribute the spaces again after the creation/destruction.
+    even_spaces(&states)?;
+
+    query()
+}
+
+const SPACE_COUNT_ATTEMPTS: u32 = 3;
+
+// Right after a reload, the scripting addition can fail a space command or
+// two before it is ready.
+fn is_transient_space_error(e: &anyhow::Error) -> bool {
+    let message = e.to_string();
+    message.contains("scripting-addition") || message.contains("scripting addition")
+}
+
+// Send a space --create or --destroy, retrying while the scripting addition
+// isn't ready. Returns false when it gave up.
+fn space_count_message(msgs: &[&str]) -> Result<bool> {
+    for attempt in 1..=SPACE_COUNT_ATTEMPTS {
+        match yabai_message(msgs) {
+            Ok(_) => return Ok(true),
+            Err(e) if is_transient_space_error(&e) => {
+                debug!("{:?} failed: {}, attempt {}", msgs, e, attempt);
+                thread::sleep(Duration::from_millis(500));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(false)
+}
+
+// Create or destroy spaces towards target, keeping up to keep. Returns
+// whether there was anything to do.
+fn adjust_space_count(
+    states: &YabaiStates,
+    target: u32,
+    keep: u32,
+    keep_windows: bool,
+) -> Result<bool> {
+    let mut adjusted = false;
+    if states.num_spaces() < target {
+        info!(
+            "{} spaces, creating {}",
+            states.num_spaces(),
+            target - states.num_spaces()
+        );
+        for _i in states.num_spaces()..target {
+            space_count_message(&["space", "--create"])?;
+            adjusted = true;
+        }
+    } else if states.num_spaces() > keep {
+        // From the top down, so skipping a space doesn't shift the indices
+        // of the ones still to go.
+        for space_index in (keep + 1..=states.num_spaces()).rev() {
+            if keep_windows && states.space_has_windows(space_index) {
+                info!("Not destroying space {}. It has windows", space_index);
+                continue;
+            }
+            space_count_message(&["space", &space_index.to_string(), "--destroy"])?;
+            adjusted = true;
         }
-        SpaceArg::Prev => {
-            if focused_label_index <= display_count {
-                let extra_monitors = if states.num_displays() > 2 {
-                    states.num_displays() - 2
+    }
+    Ok(adjusted)
+}
+
+// The space-count half of restore: fix the number of spaces on each display
+// and relabel them, without touching layouts or windows.
+pub fn equalize_spaces() -> Result<()> {
+    if is_labels_only() {
+        bail!("Creating and destroying spaces needs the scripting addition");
+    }
+    let states = query()?;
+    validate_space_count(num_spaces(), &SpacePlan::for_states(&states)?)?;
+    let states = fix_space_count(&states, true)?;
+    let states = ensure_labels(&states)?;
+    save_states(&states)?;
+    Ok(())
+}
+
+// The label of the space at the given yabai index.
+fn space_label(space_index: u32, num_displays: u32, mode: FallbackMode) -> Result<String> {
+    // Desktop 1 is reserved. We don't put anything there because of this apple
+    // issue:
+    //
+    // https://github.com/koekeishiya/yabai/discussions/238#discussioncomment-193399
+    if space_index == 1 {
+        return Ok("reserved".to_string());
+    }
+    let i = space_index - 1;
+
+    let label_index = match num_displays {
+        // One monitor is easy. Just label Desktop 2 as s1, D3 as s2, D4 as
+        // s3, and so on. (Again, as mentioned above, we leave Desktop 1
+        // unused to get around a quirk in MacOS).
+        1 => i,
+        // This is the arrangement for two monitors with the one on the
+        // right as primary:
+        //
+        // Right monitor:
+        //
+        // reserved s2 s4 s6 s8 s10 <= yabai space labels
+        // Desktop1 D2 D3 D4 D5 D6  <= MacOS Desktop
+        //
+        // Left monitor:
+        //
+        // s1 s3 s5 s7  s9
+        // D7 D8 D9 D10 D11
+        //
+        // With this arrangement, s1 and s2 form a single composite desktop,
+        // so are s3 and s4, s5 and s6, and so on.
+        //
+        // The `focus_space` subcommand would switch two monitors in unison
+        // as a single desktop.
+        //
+        // With fallback_mode = "mirror", displays 3 and 4 repeat the same
+        // arrangement with s11 to s20, displays 5 and 6 with s21 to s30,
+        // and so on. Any display left over gets a single space.
+        _ => {
+            let (pairs, _) = extra_displays(num_displays, mode)?;
+            if i <= num_spaces() * (pairs + 1) {
+                let pair = (i - 1) / num_spaces();
+                let i = (i - 1) % num_spaces() + 1;
+                if i <= num_spaces() / 2 {
+                    pair * num_spaces() + i * 2
                 } else {
-                    0
-                };
-                states.num_spaces() - 1 /* reserved */ - extra_monitors - (display_count - focused_label_index)
+                    pair * num_spaces() + (i - num_spaces() / 2) * 2 - 1
+                }
+            } else {
+                i
+            }
+        }
+    };
+    Ok(format!("s{}", label_index))
+}
+
+// The label of the given space in labels-only mode, going by its position
+// on its display. This follows the same scheme as space_label() as far as
+// the existing spaces go: reserved, s2, s4, ... on display 1, s1, s3, ... on
+// display 2 and a single space on every display after that. Anything beyond
+// gets no label, except on a single display, where s1 and up simply carry
+// on for as many spaces as there are.
+fn display_space_label(states: &YabaiStates, space: &Space) -> String {
+    let position = states
+        .spaces
+        .iter()
+        .filter(|other| other.display == space.display && other.index < space.index)
+        .count() as u32;
+    if space.display == 1 && position == 0 {
+        return "reserved".to_string();
+    }
+    let label_index = match (states.num_displays(), space.display) {
+        (1, _) => position,
+        (_, 1) => position * 2,
+        (_, 2) => position * 2 + 1,
+        (_, display) if position == 0 => num_spaces() + display - 2,
+        _ => return String::new(),
+    };
+    if label_index > num_spaces() && space.display <= 2 && states.num_displays() > 1 {
+        return String::new();
+    }
+    let label = format!("s{}", label_index);
+    if states.sides_swapped && states.num_displays() > 1 {
+        return swap_side(&label, space.display);
+    }
+    label
+}
+
+// The labels-only counterpart of SpacePlan::labels(), for the spaces as they
+// are.
+fn display_label_plan(states: &YabaiStates) -> Vec<(u32, String)> {
+    states
+        .spaces
+        .iter()
+        .map(|space| (space.index, display_space_label(states, space)))
+        .collect()
+}
+
+fn planned_labels(states: &YabaiStates) -> Result<Vec<(u32, String)>> {
+    if is_labels_only() {
+        return Ok(display_label_plan(states));
+    }
+    SpacePlan::for_states(states)?.labels(states.num_spaces())
+}
+
+fn labels_needed(states: &YabaiStates) -> Result<Option<String>> {
+    for (space, (_, label)) in states.spaces.iter().zip(display_label_plan(states)) {
+        if space.label != label {
+            return Ok(Some(format!(
+                "space {} is labeled {:?}, expected {:?}",
+                space.index, space.label, label
+            )));
+        }
+        if label == "reserved" && !space.windows.is_empty() {
+            return Ok(Some("the reserved space has windows".to_string()));
+        }
+    }
+    Ok(None)
+}
+
+fn apply_label_plan(states: &YabaiStates, plan: &[(u32, String)]) -> Result<()> {
+    for (space_index, label) in plan.iter() {
+        let current = states
+            .spaces
+            .iter()
+            .find(|space| space.index == *space_index)
+            .map(|space| space.label.as_str());
+        if current != Some(label.as_str()) {
+            label_space(*space_index, label)?;
+        }
+    }
+    Ok(())
+}
+
+fn ensure_labels(states: &YabaiStates) -> Result<YabaiStates> {
+    apply_label_plan(states, &planned_labels(states)?)?;
+    let states = query()?;
+    if is_labels_only() {
+        let missing: Vec<String> = (1..=num_spaces())
+            .filter(|&label_index| states.find_space_by_label_index(label_index).is_none())
+            .map(|label_index| format!("s{}", label_index))
+            .collect();
+        if !missing.is_empty() {
+            warn!(
+                "No space for {}. Create more spaces in Mission Control",
+                missing.join(", ")
+            );
+        }
+    }
+    Ok(states)
+}
+
+// Just the labeling of a restore, for when only the labels drifted. Spaces
+// that already carry the right label are left alone.
+pub fn relabel() -> Result<()> {
+    let states = query_settled()?;
+    let states = ensure_labels(&states)?;
+    if let Some(reason) = restore_needed(&states)? {
+        warn!("Relabeled, but restore-spaces is still needed: {}", reason);
+    }
+    warn_pairing(&states);
+    save_states(&states)?;
+    Ok(())
+}
+
+// After some hotplug sequences the focused display can briefly hold nothing
+// but unlabeled spaces. Label just that display so navigation has something
+// to work from.
+fn ensure_focused_display_labeled(states: YabaiStates) -> Result<YabaiStates> {
+    let display = match states.focused_display_index() {
+        Some(display) if states.focused_display_unlabeled() => display,
+        _ => return Ok(states),
+    };
+    info!(
+        "Focused display {} has no labeled spaces, relabeling",
+        display
+    );
+    let plan: Vec<(u32, String)> = planned_labels(&states)?
+        .into_iter()
+        .filter(|(space_index, _)| {
+            states
+                .spaces
+                .iter()
+                .any(|space| space.index == *space_index && space.display == display)
+        })
+        .collect();
+    apply_label_plan(&states, &plan)?;
+    query()
+}
+
+// Where the windows found on the reserved space go: reserved_destination,
+// or s1 when there is no such space.
+fn reserved_destination(states: &YabaiStates) -> &'static str {
+    let destination = config::get().reserved_destination.as_str();
+    if destination == "s1"
+        || (destination != "reserved" && states.find_space_by_label(destination).is_some())
+    {
+        return destination;
+    }
+    warn!(
+        "No space {:?} for the windows on the reserved space, using s1",
+        destination
+    );
+    "s1"
+}
+
+// Returns the new states and whether any window was moved. With only_app,
+// windows of other apps are left where they are.
+fn reorganize_spaces(states: &YabaiStates, only_app: Option<&str>) -> Result<(YabaiStates, bool)> {
+    let old_states = states::load_yabai_or_default()?;
+    let mut seen = HashSet::new();
+    let mut moved = false;
+    let reserved_destination = reserved_destination(states);
+
+    for space in old_states.spaces.iter() {
+        for window_id in space.windows.iter() {
+            // Only act on the space the window actually belonged to, and only
+            // once, even if the saved windows arrays list it twice.
+            if old_states
+                .find_window_id_in_space(&space.label, window_id)
+                .is_none()
+                || !seen.insert(*window_id)
+            {
+                continue;
+            }
+            if let Some(app) = only_app {
+                let window_app = states
+                    .find_window(window_id)
+                    .or_else(|| old_states.find_window(window_id))
+                    .map(|window| window.app.as_str());
+                if window_app != Some(app) {
+                    continue;
+                }
+            }
+            if !states.can_move(window_id) {
+                info!("Not moving {}. yabai says it can't be moved", window_id);
+                continue;
+            }
+            if space.label == "reserved" {
+                move_window_to_space(window_id, reserved_destination)?;
+                moved = true;
+            } else if states
+                .find_window_id_in_space(&space.label, window_id)
+                .is_none()
+            {
+                move_window_to_space(window_id, &space.label)?;
+                moved = true;
+            }
+        }
+    }
+
+    Ok((query()?, moved))
+}
+
+// Where reorganize --live sends windows, as (window id, label), going by
+// only where they are now and the placements config: an app's windows go to
+// its placement, and whatever is left on the reserved space goes to the
+// reserved destination. The rest stay.
+fn live_destinations(
+    states: &YabaiStates,
+    placements: &BTreeMap<String, String>,
+    reserved_destination: &str,
+) -> Vec<(u32, String)> {
+    let mut destinations = Vec::new();
+    for window in states.windows.iter() {
+        let label = match states
+            .spaces
+            .iter()
+            .find(|space| space.index == window.space)
+        {
+            Some(space) => space.label.as_str(),
+            None => continue,
+        };
+        let destination = match placements.get(&window.app) {
+            Some(placement) if states.find_space_by_label(placement).is_some() => placement,
+            _ if label == "reserved" => reserved_destination,
+            _ => continue,
+        };
+        if destination != label {
+            destinations.push((window.id, destination.to_string()));
+        }
+    }
+    destinations
+}
+
+// reorganize_spaces(), trusting each window's space as yabai reports it now
+// instead of the last saved states.
+fn reorganize_live(states: &YabaiStates, only_app: Option<&str>) -> Result<YabaiStates> {
+    let destinations = live_destinations(
+        states,
+        &config::get().placements,
+        reserved_destination(states),
+    );
+    for (window_id, label) in destinations.iter() {
+        if only_app.is_some_and(|app| {
+            states
+                .find_window(window_id)
+                .is_none_or(|window| window.app != app)
+        }) {
+            continue;
+        }
+        if !states.can_move(window_id) {
+            info!("Not moving {}. yabai says it can't be moved", window_id);
+            continue;
+        }
+        move_window_to_space(window_id, label)?;
+    }
+    query()
+}
+
+fn wait_for_yabai(timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if yabai_message(&["query", "--displays"]).is_ok() {
+            return Ok(());
+        }
+        if Instant::now() > deadline {
+            bail!("yabai did not come back within {:?}", timeout);
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+pub fn force_reload() -> Result<()> {
+    if is_dry_run() {
+        println!("yabai --restart-service");
+        return restore_spaces(&RestoreOptions::default());
+    }
+    let status = Command::new("yabai")
+        .arg("--restart-service")
+        .status()
+        .context("Failed to run yabai --restart-service")?;
+    if !status.success() {
+        bail!("yabai --restart-service exited with {}", status);
+    }
+    // The old instance can keep answering on the socket for a moment while
+    // it shuts down. Give it time to go away before polling for the new one.
+    thread::sleep(Duration::from_secs(1));
+    wait_for_yabai(Duration::from_secs(30))?;
+    restore_spaces(&RestoreOptions::default())
+}
+
+// "yabai-v7.1.1" as printed by yabai --version.
+fn parse_yabai_version(output: &str) -> Option<(u32, u32, u32)> {
+    let version = output
+        .trim()
+        .trim_start_matches("yabai-")
+        .trim_start_matches('v');
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().unwrap_or(Some(0))?;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    Some((major, minor, patch))
+}
+
+// The composite desktops that can't work the way they should: a label whose
+// partner doesn't exist, or sits on the same display. Navigation then only
+// focuses that one space.
+fn pairing_problems(states: &YabaiStates) -> Vec<String> {
+    let mut problems = Vec::new();
+    if states.composite_displays() < 2 {
+        return problems;
+    }
+    for label_index in 1..=num_spaces() {
+        let space = match states.find_space_by_label_index(label_index) {
+            Some(space) => space,
+            None => continue,
+        };
+        let partner = partner_label_index(label_index);
+        match states.find_space_by_label_index(partner) {
+            None => problems.push(format!("s{} has no partner s{}", label_index, partner)),
+            Some(other) if other.display == space.display && label_index < partner => problems
+                .push(format!(
+                    "s{} and s{} are both on display {}",
+                    label_index, partner, space.display
+                )),
+            Some(_) => {}
+        }
+    }
+    problems
+}
+
+fn warn_pairing(states: &YabaiStates) {
+    for problem in pairing_problems(states) {
+        warn!("{}, its desktop only spans one display", problem);
+    }
+}
+
+fn is_tested_version(version: (u32, u32, u32)) -> bool {
+    let (min, max) = TESTED_YABAI_VERSIONS;
+    (min..=max).contains(&(version.0, version.1))
+}
+
+// Warn when yabai is a version yabaictl hasn't been tried with, or its
+// states don't deserialize the way yabaictl expects.
+pub fn check_compat() -> Result<()> {
+    let ((min_major, min_minor), (max_major, max_minor)) = TESTED_YABAI_VERSIONS;
+    let output = Command::new("yabai")
+        .arg("--version")
+        .output()
+        .context("Failed to run yabai --version")?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let version = parse_yabai_version(&output)
+        .with_context(|| format!("Unrecognized yabai version {:?}", output.trim()))?;
+    println!(
+        "yabai {}.{}.{}, tested with {}.{} to {}.{}",
+        version.0, version.1, version.2, min_major, min_minor, max_major, max_minor
+    );
+    if !is_tested_version(version) {
+        warn!("yabaictl has not been tested with this yabai version");
+    }
+    let states = query()?;
+    if states.partial {
+        warn!("some of the states don't deserialize, see above");
+    }
+    warn_pairing(&states);
+    Ok(())
+}
+
+// Persist the latest yabai snapshot and record its focused window in the
+// recent window list.
+fn save_states(states: &YabaiStates) -> Result<()> {
+    states::save_yabai(states)?;
+    let mut ctl = states::load_yabaictl_or_default()?;
+    let window_changed = ctl.observe_focused_window(states);
+    let space_changed = ctl.observe_focused_space(states);
+    if window_changed || space_changed {
+        states::save_yabaictl(&ctl)?;
+    }
+    Ok(())
+}
+
+// Bring the other half of the composite desktop of the label index into
+// view, so focusing the label index itself shows the whole desktop.
+fn show_partner(states: &YabaiStates, label_index: u32) -> Result<()> {
+    if states.composite_displays() >= 2 && label_index <= num_spaces() {
+        let neighbor_label_index = partner_label_index(label_index);
+        if let Some(neighbor_space) = states.find_space_by_label_index(neighbor_label_index) {
+            if !neighbor_space.is_visible {
+                focus_partner(neighbor_label_index)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Focus a window, first bringing the other half of its composite desktop
+// forward so that both monitors switch together. The space of the window
+// comes from the windows arrays, so a cold snapshot is warmed up first.
+fn focus_window_composite(states: &YabaiStates, window_id: u32) -> Result<()> {
+    let warm;
+    let states = if states.is_cold() {
+        warm = query_warm()?;
+        &warm
+    } else {
+        states
+    };
+    let space = states
+        .spaces
+        .iter()
+        .find(|space| space.find_window_id(&window_id).is_some());
+    if let Some(label_index) = space.and_then(|space| space.label_index()) {
+        show_partner(states, label_index)?;
+    }
+    yabai_message(&["window", "--focus", &window_id.to_string()])?;
+    Ok(())
+}
+
+// The managed windows on the visible spaces, display by display and then
+// left to right and top to bottom. The order focus-next-window cycles
+// through.
+fn visible_window_cycle(states: &YabaiStates) -> Vec<u32> {
+    let mut windows: Vec<&Window> = states
+        .windows
+        .iter()
+        .filter(|window| !window.is_floating && !window.is_minimized && !window.is_hidden)
+        .filter(|window| {
+            states
+                .spaces
+                .iter()
+                .any(|space| space.index == window.space && space.is_visible)
+        })
+        .collect();
+    windows.sort_by(|a, b| {
+        a.display
+            .cmp(&b.display)
+            .then(a.frame.x.total_cmp(&b.frame.x))
+            .then(a.frame.y.total_cmp(&b.frame.y))
+            .then(a.id.cmp(&b.id))
+    });
+    windows.iter().map(|window| window.id).collect()
+}
+
+// Focus the next or previous window across both halves of the visible
+// composite desktop, wrapping around at either end.
+pub fn focus_next_window(forward: bool) -> Result<()> {
+    let states = query_settled()?;
+    let states = restore_if_necessary(states)?;
+    let cycle = visible_window_cycle(&states);
+    if cycle.is_empty() {
+        bail!("No windows on the visible spaces");
+    }
+    let focused = states
+        .focused_window()
+        .and_then(|window| cycle.iter().position(|id| *id == window.id));
+    let next = match (focused, forward) {
+        (Some(i), true) => (i + 1) % cycle.len(),
+        (Some(i), false) => (i + cycle.len() - 1) % cycle.len(),
+        (None, true) => 0,
+        (None, false) => cycle.len() - 1,
+    };
+    focus_window_composite(&states, cycle[next])?;
+    let states = query()?;
+    save_states(&states)?;
+    Ok(())
+}
+
+pub fn focus_recent_window() -> Result<()> {
+    let states = query_settled()?;
+    let states = restore_if_necessary(states)?;
+
+    let mut ctl = states::load_yabaictl_or_default()?;
+    ctl.observe_focused_window(&states);
+    if ctl.recent_windows.len() < 2 {
+        states::save_yabaictl(&ctl)?;
+        bail!("No recently focused window to go back to");
+    }
+    // Walk further back on every call, like holding alt and pressing tab.
+    // Focusing anything else in the meantime restarts from the top.
+    let cursor = match ctl.recent_window_cursor + 1 {
+        cursor if cursor >= ctl.recent_windows.len() => 1,
+        cursor => cursor,
+    };
+    let window_id = ctl.recent_windows[cursor];
+    focus_window_composite(&states, window_id)?;
+    ctl.recent_window_cursor = cursor;
+    states::save_yabaictl(&ctl)?;
+
+    let states = query()?;
+    save_states(&states)?;
+    Ok(())
+}
+
+// Toggle between the focused window and the one focused before it.
+pub fn focus_back() -> Result<()> {
+    let states = query_settled()?;
+    let states = restore_if_necessary(states)?;
+
+    let mut ctl = states::load_yabaictl_or_default()?;
+    ctl.observe_focused_window(&states);
+    ctl.end_window_cycle();
+    states::save_yabaictl(&ctl)?;
+    // The window may have closed since the states were saved. Fall back
+    // to the ones before it.
+    for window_id in ctl.recent_windows.iter().skip(1) {
+        match focus_window_composite(&states, *window_id) {
+            Ok(()) => {
+                let states = query()?;
+                save_states(&states)?;
+                return Ok(());
+            }
+            Err(e)
+                if e.to_string()
+                    .contains("could not locate the window to act on!") =>
+            {
+                info!("Window {} is gone, trying the one before", window_id);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    bail!("No recently focused window to go back to");
+}
+
+pub fn restore_spaces(options: &RestoreOptions) -> Result<()> {
+    let states = query_settled()?;
+    let focused_window = states.focused_window().map(|window| window.id);
+    let focused_label = states.focused_space().map(|space| space.label.clone());
+    // An unplugged display renames the labels, see merge_unplugged().
+    let renames = unplug_renames(&states)?;
+    let focused_label_index =
+        states
+            .focused_space()
+            .and_then(|space| match rename_label(&renames, &space.label) {
+                Some(label) => label[1..].parse().ok(),
+                None => space.label_index(),
+            });
+    let states = restore_spaces_core(states, options)?;
+    let states = if options.keep_focus {
+        // Put the user back exactly where they were. The window is the most
+        // precise anchor since labels may have moved around.
+        match (focused_window, focused_label) {
+            (Some(window_id), _) => focus_window_composite(&states, window_id)?,
+            (None, Some(label)) if !label.is_empty() => focus_space_arg(&label)?,
+            _ => {}
+        }
+        query()?
+    } else if options.keep_desktop {
+        // The windows went back to their labels, so the same label is the
+        // same desktop, whichever display it ended up on.
+        match focused_label_index {
+            Some(label_index) if states.find_space_by_label_index(label_index).is_some() => {
+                show_partner(&states, label_index)?;
+                focus_space_by_label(label_index)?;
+                query()?
+            }
+            _ => states,
+        }
+    } else {
+        states
+    };
+    save_states(&states)?;
+    Ok(())
+}
+
+// Whether there are spaces enough for the composite displays of the plan,
+// at least two for each. num_spaces itself is checked with the config.
+fn validate_space_count(num_spaces: u32, plan: &SpacePlan) -> Result<()> {
+    let composite_displays = plan.composite_displays();
+    if composite_displays >= 2 && num_spaces < 2 * composite_displays {
+        bail!(
+            "num_spaces must be at least {} for {} composite displays, got {}",
+            2 * composite_displays,
+            composite_displays,
+            num_spaces
+        );
+    }
+    Ok(())
+}
+
+fn restore_spaces_core(states: YabaiStates, options: &RestoreOptions) -> Result<YabaiStates> {
+    validate_space_count(num_spaces(), &SpacePlan::for_states(&states)?)?;
+    match restore_needed(&states)? {
+        None => {
+            info!("Spaces are already in order");
+            record_display_fingerprint(&states)?;
+            return Ok(states);
+        }
+        Some(reason) => info!("Restoring spaces: {}", reason),
+    }
+    merge_unplugged(&states)?;
+    let states = ensure_spaces(&states, options)?;
+    let states = ensure_labels(&states)?;
+    let states = reorganize_passes(states, None)?;
+    let states = float_kept_windows(states)?;
+    let states = restore_split_types(states)?;
+    record_display_fingerprint(&states)?;
+    prune_recent_spaces(&states)?;
+    warn_pairing(&states);
+    Ok(states)
+}
+
+// How the labels of the last saved states carry over when displays were
+// unplugged and everything lands on one display: the remaining display's
+// desktops first, then those of each unplugged display, each in their
+// original order. Empty when nothing was unplugged.
+fn merge_renames(old_states: &YabaiStates, states: &YabaiStates) -> Vec<(String, String)> {
+    let connected = |display: &Display| states.displays.iter().any(|d| d.uuid == display.uuid);
+    let mut old_displays: Vec<&Display> = old_states.displays.iter().collect();
+    if !old_displays.iter().any(|display| !connected(display)) {
+        return Vec::new();
+    }
+    // Stable, so the unplugged displays keep their order.
+    old_displays.sort_by_key(|display| !connected(display));
+
+    let mut renames = Vec::new();
+    for display in old_displays {
+        let mut label_indexes: Vec<u32> = old_states
+            .spaces
+            .iter()
+            .filter(|space| space.display == display.index)
+            .filter_map(Space::label_index)
+            .collect();
+        label_indexes.sort_unstable();
+        for label_index in label_indexes {
+            let new_label_index = renames.len() + 1;
+            renames.push((format!("s{}", label_index), format!("s{}", new_label_index)));
+        }
+    }
+    renames
+}
+
+// merge_renames() for when a display was unplugged since the last restore,
+// or else nothing.
+fn unplug_renames(states: &YabaiStates) -> Result<Vec<(String, String)>> {
+    let ctl = states::load_yabaictl_or_default()?;
+    let known_displays = ctl
+        .display_fingerprint
+        .split(';')
+        .filter(|display| !display.is_empty())
+        .count();
+    if states.partial
+        || states.composite_displays() != 1
+        || known_displays <= states.displays.len()
+        || !states::has_yabai()?
+    {
+        return Ok(Vec::new());
+    }
+    Ok(merge_renames(&states::load_yabai()?, states))
+}
+
+fn rename_label(renames: &[(String, String)], label: &str) -> Option<String> {
+    renames
+        .iter()
+        .find(|(old_label, _)| old_label == label)
+        .map(|(_, new_label)| new_label.clone())
+}
+
+// When a display was unplugged, rename the spaces of the saved states the
+// way merge_renames() says, so the rest of the restore keeps every window
+// on the desktop it was on instead of evening everything out by index.
+fn merge_unplugged(states: &YabaiStates) -> Result<()> {
+    let renames = unplug_renames(states)?;
+    if renames.is_empty() {
+        return Ok(());
+    }
+    info!("Merging the spaces of the unplugged displays");
+    let mut old_states = states::load_yabai()?;
+    for space in old_states.spaces.iter_mut() {
+        if let Some(label) = rename_label(&renames, &space.label) {
+            space.label = label;
+        }
+    }
+    states::save_yabai(&old_states)?;
+
+    let mut ctl = states::load_yabaictl_or_default()?;
+    for recent in ctl.recent_by_display.values_mut() {
+        if let Some(label) = rename_label(&renames, &format!("s{}", recent)) {
+            *recent = label[1..].parse()?;
+        }
+    }
+    states::save_yabaictl(&ctl)?;
+    Ok(())
+}
+
+fn prune_recent_spaces(states: &YabaiStates) -> Result<()> {
+    if states.partial {
+        return Ok(());
+    }
+    let mut ctl = states::load_yabaictl_or_default()?;
+    if ctl.prune_recent(states) {
+        states::save_yabaictl(&ctl)?;
+    }
+    Ok(())
+}
+
+// Remember the monitor arrangement the spaces were last put in order for.
+fn record_display_fingerprint(states: &YabaiStates) -> Result<()> {
+    if states.partial {
+        return Ok(());
+    }
+    let mut ctl = states::load_yabaictl_or_default()?;
+    let fingerprint = states.display_fingerprint();
+    if ctl.display_fingerprint != fingerprint {
+        ctl.display_fingerprint = fingerprint;
+        states::save_yabaictl(&ctl)?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct NeedsRestoreResult {
+    needed: bool,
+    reasons: Vec<String>,
+}
+
+// Whether restore, or restore_if_necessary(), would do anything, without
+// changing a thing. Returns true when it would.
+pub fn needs_restore(json: bool) -> Result<bool> {
+    let states = query_settled()?;
+    let mut reasons = Vec::new();
+    if let Some(space) = states.find_unlabeled_space() {
+        reasons.push(format!("space {} is unlabeled", space.index));
+    }
+    if let Some(reason) = restore_needed(&states)? {
+        reasons.push(reason);
+    }
+    let ctl = states::load_yabaictl_or_default()?;
+    if !states.partial && ctl.display_fingerprint != states.display_fingerprint() {
+        reasons.push("the displays changed since the last restore".to_string());
+    }
+
+    let result = NeedsRestoreResult {
+        needed: !reasons.is_empty(),
+        reasons,
+    };
+    if json {
+        println!("{}", serde_json::to_string(&result)?);
+    } else {
+        for reason in result.reasons.iter() {
+            println!("{}", reason);
+        }
+    }
+    Ok(result.needed)
+}
+
+// Cheap enough for a signal handler that fires on every space change: only
+// restore when the displays are not the ones the last restore saw.
+pub fn restore_if_displays_changed(options: &RestoreOptions) -> Result<()> {
+    let states = query_settled()?;
+    let ctl = states::load_yabaictl_or_default()?;
+    if !states.partial && ctl.display_fingerprint == states.display_fingerprint() {
+        info!("Displays unchanged");
+        return Ok(());
+    }
+    restore_spaces(options)
+}
+
+fn is_split(split_type: &str) -> bool {
+    split_type == "horizontal" || split_type == "vertical"
+}
+
+// Best effort at keeping the bsp split orientation of windows across a
+// restore, going by the last saved states. Toggling the split of a window
+// flips its parent node, so its sibling changes as well. Re-query after
+// every toggle, and toggle each window at most once.
+fn restore_split_types(mut states: YabaiStates) -> Result<YabaiStates> {
+    let old_states = states::load_yabai_or_default()?;
+    let split_types: HashMap<WindowKey, &str> = old_states
+        .windows
+        .iter()
+        .filter(|window| is_split(&window.split_type))
+        .map(|window| (window.key(), window.split_type.as_str()))
+        .collect();
+    let mut toggled = HashSet::new();
+    loop {
+        let mismatched = states.windows.iter().find(|window| {
+            is_split(&window.split_type)
+                && !toggled.contains(&window.id)
+                && split_types
+                    .get(&window.key())
+                    .is_some_and(|&split_type| split_type != window.split_type)
+        });
+        let window_id = match mismatched {
+            None => return Ok(states),
+            Some(window) => window.id,
+        };
+        yabai_message(&["window", &window_id.to_string(), "--toggle", "split"])?;
+        toggled.insert(window_id);
+        if !is_dry_run() {
+            states = query()?;
+        }
+    }
+}
+
+// Probably a yabai bug somehwere. When this is called by yabai on a signal
+// of the display_added event, sending a window to a different space
+// sometimes doesn't take effect. So, reorganize up to reorganize_passes
+// times, for as long as windows still get moved.
+fn reorganize_passes(mut states: YabaiStates, only_app: Option<&str>) -> Result<YabaiStates> {
+    for _ in 0..config::get().reorganize_passes.max(1) {
+        let (new_states, moved) = reorganize_spaces(&states, only_app)?;
+        states = new_states;
+        if !moved {
+            break;
+        }
+    }
+    Ok(states)
+}
+
+// Run every phase of a restore, whether or not one is needed, and print how
+// long each took and how many yabai calls it made.
+pub fn bench_restore() -> Result<()> {
+    let mut phases: Vec<(String, Duration, u32)> = Vec::new();
+    let mut timed = |name: String, phase: &mut dyn FnMut() -> Result<YabaiStates>| {
+        let calls = YABAI_CALLS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        let states = phase()?;
+        phases.push((
+            name,
+            start.elapsed(),
+            YABAI_CALLS.load(Ordering::Relaxed) - calls,
+        ));
+        Ok::<YabaiStates, anyhow::Error>(states)
+    };
+
+    let states = timed("query".to_string(), &mut query_settled)?;
+    validate_space_count(num_spaces(), &SpacePlan::for_states(&states)?)?;
+    let options = RestoreOptions {
+        keep_focus: false,
+        keep_desktop: false,
+    };
+    let states = timed("ensure_spaces".to_string(), &mut || {
+        ensure_spaces(&states, &options)
+    })?;
+    let mut states = timed("ensure_labels".to_string(), &mut || ensure_labels(&states))?;
+    for pass in 1..=config::get().reorganize_passes.max(1) {
+        states = timed(format!("reorganize {}", pass), &mut || {
+            reorganize_spaces(&states, None).map(|(states, _)| states)
+        })?;
+    }
+
+    let mut total = Duration::ZERO;
+    let mut total_calls = 0;
+    for (name, duration, calls) in phases.iter() {
+        println!(
+            "{:<16}{:>8}ms{:>6} calls",
+            name,
+            duration.as_millis(),
+            calls
+        );
+        total += *duration;
+        total_calls += calls;
+    }
+    println!(
+        "{:<16}{:>8}ms{:>6} calls",
+        "total",
+        total.as_millis(),
+        total_calls
+    );
+    save_states(&states)?;
+    Ok(())
+}
+
+// Send windows back to the spaces they were last seen on, without touching
+// the spaces themselves.
+pub fn reorganize(only_app: Option<&str>, live: bool) -> Result<()> {
+    let states = query()?;
+    let states = if live {
+        reorganize_live(&states, only_app)?
+    } else {
+        reorganize_passes(states, only_app)?
+    };
+    save_states(&states)?;
+    Ok(())
+}
+
+// Windows put under toggle-manage stay floating across restores.
+fn float_kept_windows(states: YabaiStates) -> Result<YabaiStates> {
+    let ctl = states::load_yabaictl_or_default()?;
+    let mut changed = false;
+    for window in states.windows.iter() {
+        if !window.is_floating && ctl.keep_floating.contains(&window.key()) {
+            yabai_message(&["window", &window.id.to_string(), "--toggle", "float"])?;
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(states);
+    }
+    query()
+}
+
+pub fn toggle_manage() -> Result<()> {
+    let states = query()?;
+    let window = states.focused_window().context("No focused window")?;
+
+    yabai_message(&["window", "--toggle", "float"])?;
+    let mut ctl = states::load_yabaictl_or_default()?;
+    if window.is_floating {
+        ctl.keep_floating.remove(&window.key());
+    } else {
+        ctl.keep_floating.insert(window.key());
+    }
+    states::save_yabaictl(&ctl)?;
+
+    let states = query()?;
+    save_states(&states)?;
+    Ok(())
+}
+
+fn restore_if_necessary(states: YabaiStates) -> Result<YabaiStates> {
+    if states.find_unlabeled_space().is_none() {
+        return Ok(states);
+    }
+    if states::is_paused()? {
+        info!("Not restoring, yabaictl is paused");
+        return Ok(states);
+    }
+    let states = restore_spaces_core(states, &RestoreOptions::default())?;
+    Ok(states)
+}
+
+// Stop, or start again, restoring from within the other commands when they
+// find an unlabeled space. restore-spaces always restores.
+pub fn pause(paused: bool) -> Result<()> {
+    states::set_paused(paused)?;
+    if paused {
+        println!("Paused. Only restore-spaces restores until resume");
+    }
+    Ok(())
+}
+
+// The label index one desktop over, staying on the same side of the
+// composite desktops and wrapping around at either end.
+fn step_label_index(label_index: u32, display_count: u32, forward: bool) -> u32 {
+    if forward {
+        let index = label_index + display_count;
+        if index > num_spaces() {
+            index % num_spaces()
+        } else {
+            index
+        }
+    } else if label_index <= display_count {
+        num_spaces() - (display_count - label_index)
+    } else {
+        label_index - display_count
+    }
+}
+
+// Make sure there is a space to focus for the label index, as configured
+// by missing_space. yabai only half fails on a label that doesn't exist.
+fn ensure_space_exists(states: YabaiStates, label_index: u32) -> Result<YabaiStates> {
+    if states.find_space_by_label_index(label_index).is_some() {
+        return Ok(states);
+    }
+    if config::get().missing_space == MissingSpace::Bail {
+        bail!("Space s{} does not exist; run restore-spaces", label_index);
+    }
+    info!("Space s{} does not exist, restoring", label_index);
+    let states = restore_spaces_core(states, &RestoreOptions::default())?;
+    if states.find_space_by_label_index(label_index).is_none() {
+        bail!(
+            "Space s{} does not exist, even after a restore",
+            label_index
+        );
+    }
+    Ok(states)
+}
+
+// Where next and prev go from. A fullscreen app's space has no label, so
+// go from the labeled space the display was on before instead.
+fn focused_label_index(states: &YabaiStates, display_uuid: &str) -> Result<u32> {
+    let focused_space = states.focused_space().expect("No focused space found");
+    if let Some(label_index) = focused_space.label_index() {
+        return Ok(label_index);
+    }
+    let ctl = states::load_yabaictl_or_default()?;
+    Ok(ctl
+        .last_labeled_by_display
+        .get(display_uuid)
+        .copied()
+        .unwrap_or(0))
+}
+
+// The recent space of the display, as recorded and as clamped to the spaces
+// that exist.
+fn recent_label_index(
+    states: &YabaiStates,
+    ctl: &states::YabaictlStates,
+    display_uuid: &str,
+) -> Result<(u32, u32)> {
+    let recent = *ctl
+        .recent_by_display
+        .get(display_uuid)
+        .context("No recent space for the focused display")?;
+    let clamped = states
+        .valid_recent(recent)
+        .with_context(|| format!("No space to fall back to from s{}", recent))?;
+    Ok((recent, clamped))
+}
+
+// The label index focus-space goes to from the focused label index.
+fn resolve_focus_target(
+    states: &YabaiStates,
+    space: SpaceArg,
+    focused_label_index: u32,
+    display_uuid: &str,
+) -> Result<u32> {
+    let display_count = if states.composite_displays() >= 2 {
+        2
+    } else {
+        1
+    };
+    let label_index = match space {
+        SpaceArg::Recent => {
+            let ctl = states::load_yabaictl_or_default()?;
+            recent_label_index(states, &ctl, display_uuid)?.1
+        }
+        SpaceArg::Next => step_label_index(focused_label_index, display_count, true),
+        SpaceArg::Prev => step_label_index(focused_label_index, display_count, false),
+        SpaceArg::Third => num_spaces() + 1,
+        SpaceArg::Fourth => num_spaces() + 2,
+        SpaceArg::Space(number) => number,
+    };
+    Ok(label_index)
+}
+
+// Print where focus-space and focus-window would go from the focused space,
+// without going anywhere.
+pub fn explain_nav() -> Result<()> {
+    let states = query_settled()?;
+    let focused_space = states.focused_space().expect("No focused space found");
+    let display_uuid = states.display_uuid(
+        states
+            .focused_display_index()
+            .expect("No focused display found"),
+    );
+    let focused_label_index = focused_label_index(&states, &display_uuid)?;
+    let describe = |space: Option<&Space>| match space {
+        Some(space) => format!("{}\tdisplay {}", space.label, space.display),
+        None => "none".to_string(),
+    };
+    println!("focused\t{}", describe(Some(focused_space)));
+    if focused_space.label_index().is_none() {
+        println!("from\ts{}", focused_label_index);
+    }
+    for (name, arg) in [
+        ("next", SpaceArg::Next),
+        ("prev", SpaceArg::Prev),
+        ("recent", SpaceArg::Recent),
+    ] {
+        let target = match resolve_focus_target(&states, arg, focused_label_index, &display_uuid) {
+            Ok(label_index) => {
+                // avoid_reserved(), minus the message.
+                let label_index = if label_index == 0 { 1 } else { label_index };
+                match states.find_space_by_label_index(label_index) {
+                    Some(space) => describe(Some(space)),
+                    None => format!("s{}\tmissing", label_index),
+                }
+            }
+            Err(e) => format!("none\t{}", e),
+        };
+        println!("{}\t{}", name, target);
+    }
+    for (name, direction) in [("east", WindowArg::East), ("west", WindowArg::West)] {
+        let neighbor = if states.composite_displays() >= 2 {
+            neighbor_space(&states, direction)
+        } else {
+            None
+        };
+        println!("{}\t{}", name, describe(neighbor));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct FocusSpaceResult {
+    label: String,
+    previous_label: String,
+    display: String,
+    previous_recent: Option<u32>,
+    recent: u32,
+}
+
+pub fn focus_space(space: SpaceArg, json: bool) -> Result<()> {
+    // Holding down a focus key fires a new yabaictl before the last one is
+    // done. Skip straight to the latest instead of queueing up behind the
+    // others.
+    let _coalesced = match states::coalesce("focus")? {
+        None => return Ok(()),
+        Some(coalesced) => coalesced,
+    };
+    let states = query_settled()?;
+    let states = restore_if_necessary(states)?;
+    let states = ensure_focused_display_labeled(states)?;
+
+    let focused_space = states.focused_space().expect("No focused space found");
+    let focused_label = focused_space.label.clone();
+    let display_uuid = states.display_uuid(
+        states
+            .focused_display_index()
+            .expect("No focused display found"),
+    );
+    let focused_label_index = focused_label_index(&states, &display_uuid)?;
+    if space == SpaceArg::Recent {
+        let mut ctl = states::load_yabaictl_or_default()?;
+        let (recent, clamped) = recent_label_index(&states, &ctl, &display_uuid)?;
+        if clamped != recent {
+            // The space went away, most likely destroyed by a restore.
+            warn!(
+                "Recent space s{} no longer exists ({} spaces), using s{}",
+                recent,
+                states.num_spaces(),
+                clamped
+            );
+            ctl.recent_by_display.insert(display_uuid.clone(), clamped);
+            states::save_yabaictl(&ctl)?;
+        }
+    }
+    let label_index = resolve_focus_target(&states, space, focused_label_index, &display_uuid)?;
+    let label_index = avoid_reserved(label_index);
+    debug!("focus_space: label_index={}", label_index);
+    let states = ensure_space_exists(states, label_index)?;
+    match states.composite_displays() {
+        1 => {
+            focus_space_by_label(label_index)?;
+        }
+        _ => {
+            // This is to bring both desktops to focus
+            let neighbor_label_index = partner_label_index(label_index);
+            let neighbor_space = states.find_space_by_label_index(neighbor_label_index);
+            match neighbor_space {
+                // Skip bringing the other screen to focus if it is already in focus or visible
+                Some(neighbor_space) => {
+                    if focused_label_index != neighbor_label_index && !neighbor_space.is_visible {
+                        focus_partner(neighbor_label_index)?;
+                    }
+                }
+                None => info!(
+                    "s{} has no partner s{}, focusing it alone",
+                    label_index, neighbor_label_index
+                ),
+            }
+            focus_space_by_label(label_index)?;
+        }
+    }
+
+    let mut ctl = states::load_yabaictl_or_default()?;
+    let previous_recent = ctl
+        .recent_by_display
+        .insert(display_uuid.clone(), focused_label_index);
+    states::save_yabaictl(&ctl)?;
+    if json {
+        let result = FocusSpaceResult {
+            label: format!("s{}", label_index),
+            previous_label: focused_label,
+            display: display_uuid,
+            previous_recent,
+            recent: focused_label_index,
+        };
+        println!("{}", serde_json::to_string(&result)?);
+    }
+    let states = query()?;
+    save_states(&states)?;
+    Ok(())
+}
+
+// Like focus-space next/prev, but skips the desktops without any windows.
+pub fn focus_used(forward: bool) -> Result<()> {
+    let states = query_warm()?;
+    let states = restore_if_necessary(states)?;
+    let states = ensure_focused_display_labeled(states)?;
+
+    let display_uuid = states.display_uuid(
+        states
+            .focused_display_index()
+            .expect("No focused display found"),
+    );
+    let focused_label_index = focused_label_index(&states, &display_uuid)?;
+    let display_count = if states.composite_displays() >= 2 {
+        2
+    } else {
+        1
+    };
+    let is_empty = |label_index: u32| {
+        states
+            .find_space_by_label_index(label_index)
+            .is_none_or(|space| space.windows.is_empty())
+    };
+
+    let mut label_index = focused_label_index;
+    for _ in 0..num_spaces() / display_count {
+        label_index = step_label_index(label_index, display_count, forward);
+        if label_index == focused_label_index {
+            break;
+        }
+        let empty = if display_count == 2 {
+            is_empty(label_index) && is_empty(partner_label_index(label_index))
+        } else {
+            is_empty(label_index)
+        };
+        if !empty {
+            return focus_space(SpaceArg::Space(label_index), false);
+        }
+    }
+    warn!("No other desktop has any windows");
+    Ok(())
+}
+
+pub fn focus_by_area(largest: bool) -> Result<()> {
+    let states = query_settled()?;
+    let states = restore_if_necessary(states)?;
+
+    let space = states.focused_space().expect("No focused space found");
+    let windows = space
+        .windows
+        .iter()
+        .filter_map(|window_id| states.find_window(window_id))
+        .filter(|window| !window.is_minimized && !window.is_hidden);
+    let window = if largest {
+        windows.max_by(|a, b| a.frame.area().total_cmp(&b.frame.area()))
+    } else {
+        windows.min_by(|a, b| a.frame.area().total_cmp(&b.frame.area()))
+    };
+    let window = match window {
+        None => bail!("No window to focus on {}", space.label),
+        Some(window) => window,
+    };
+    yabai_message(&["window", "--focus", &window.id.to_string()])?;
+
+    let states = query()?;
+    save_states(&states)?;
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct WindowSummary<'a> {
+    id: u32,
+    app: &'a str,
+    title: &'a str,
+    floating: bool,
+    minimized: bool,
+}
+
+impl<'a> WindowSummary<'a> {
+    fn new(window: &'a Window) -> Self {
+        WindowSummary {
+            id: window.id,
+            app: &window.app,
+            title: &window.title,
+            floating: window.is_floating,
+            minimized: window.is_minimized,
+        }
+    }
+
+    fn print(&self) {
+        let mut flags = Vec::new();
+        if self.floating {
+            flags.push("floating");
+        }
+        if self.minimized {
+            flags.push("minimized");
+        }
+        println!(
+            "{}\t{}\t{}\t{}",
+            self.id,
+            self.app,
+            self.title,
+            flags.join(",")
+        );
+    }
+}
+
+// The composite desktop a label index belongs to: s1 and s2 make desktop 1,
+// s3 and s4 desktop 2 and so on. With a single display, every space is a
+// desktop of its own. Spaces beyond num_spaces belong to none.
+fn desktop_number(label_index: u32, composite_displays: u32) -> Option<u32> {
+    if label_index == 0 || label_index > num_spaces() {
+        return None;
+    }
+    if composite_displays >= 2 {
+        Some(label_index.div_ceil(2))
+    } else {
+        Some(label_index)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct WindowMapEntry<'a> {
+    id: u32,
+    app: &'a str,
+    title: &'a str,
+    label: &'a str,
+    desktop: Option<u32>,
+}
+
+// Every managed window and the composite desktop it is on.
+pub fn window_map(json: bool) -> Result<()> {
+    let states = query()?;
+    let entries: Vec<WindowMapEntry> = states
+        .windows
+        .iter()
+        .filter(|window| !window.is_floating && !window.is_minimized && !window.is_hidden)
+        .map(|window| {
+            let space = states
+                .spaces
+                .iter()
+                .find(|space| space.index == window.space);
+            WindowMapEntry {
+                id: window.id,
+                app: &window.app,
+                title: &window.title,
+                label: space.map_or("", |space| space.label.as_str()),
+                desktop: space
+                    .and_then(Space::label_index)
+                    .and_then(|index| desktop_number(index, states.composite_displays())),
+            }
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string(&entries)?);
+        return Ok(());
+    }
+    for entry in entries.iter() {
+        let desktop = entry
+            .desktop
+            .map_or_else(|| "-".to_string(), |desktop| desktop.to_string());
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            desktop, entry.label, entry.id, entry.app, entry.title
+        );
+    }
+    Ok(())
+}
+
+// The spaces on the given display, or all of them.
+fn spaces_on_display(states: &YabaiStates, display: Option<u32>) -> Vec<&Space> {
+    states
+        .spaces
+        .iter()
+        .filter(|space| display.is_none_or(|display| space.display == display))
+        .collect()
+}
+
+// The windows of one space, or of every space, going by the display of the
+// spaces with --display.
+pub fn list_windows(space: Option<u32>, json: bool, display: Option<u32>) -> Result<()> {
+    let states = query_warm()?;
+    if let Some(space) = space {
+        states
+            .find_space_by_label_index(space)
+            .with_context(|| format!("Space s{} not found", space))?;
+    }
+
+    let windows: Vec<WindowSummary> = spaces_on_display(&states, display)
+        .into_iter()
+        .filter(|candidate| space.is_none_or(|space| candidate.label_index() == Some(space)))
+        .flat_map(|space| space.windows.iter())
+        .filter_map(|window_id| states.find_window(window_id))
+        .map(WindowSummary::new)
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string(&windows)?);
+        return Ok(());
+    }
+    for window in windows.iter() {
+        window.print();
+    }
+    Ok(())
+}
+
+pub fn focused_window(json: bool) -> Result<()> {
+    let states = query()?;
+    let window = WindowSummary::new(states.focused_window().context("No focused window")?);
+    if json {
+        println!("{}", serde_json::to_string(&window)?);
+    } else {
+        window.print();
+    }
+    Ok(())
+}
+
+fn print_problems(problems: &[String]) {
+    for problem in problems.iter() {
+        warn!("Mismatch: {}", problem);
+    }
+}
+
+// Print the saved states as parsed, along with whatever doesn't add up in
+// them, or delete them with clear.
+pub fn show_state(which: Option<StateArg>, clear: bool) -> Result<()> {
+    let show_yabai = which != Some(StateArg::Yabaictl);
+    let show_yabaictl = which != Some(StateArg::Yabai);
+
+    if clear {
+        if show_yabai && !states::clear_yabai()? {
+            println!("No saved yabai state");
+        }
+        if show_yabaictl && !states::clear_yabaictl()? {
+            println!("No saved yabaictl state");
+        }
+        return Ok(());
+    }
+
+    if show_yabai {
+        if states::has_yabai()? {
+            let saved = states::load_yabai()?;
+            println!("{}", serde_json::to_string_pretty(&saved)?);
+            print_problems(&saved.problems());
+        } else {
+            println!("No saved yabai state");
+        }
+    }
+    if show_yabaictl {
+        if states::has_yabaictl()? {
+            let ctl = states::load_yabaictl()?;
+            println!("{}", serde_json::to_string_pretty(&ctl)?);
+            // Check against what yabai says now, or the last saved states
+            // when it doesn't answer.
+            let live = match query() {
+                Ok(live) => live,
+                Err(_) => states::load_yabai()?,
+            };
+            print_problems(&ctl.problems(&live));
+        } else {
+            println!("No saved yabaictl state");
+        }
+    }
+    Ok(())
+}
+
+// Write the live states in the format of tests/fixtures, to reproduce a
+// problem with.
+pub fn export_state(path: &Path) -> Result<()> {
+    let states = query()?;
+    if states.partial {
+        warn!("Some of the states could not be queried, the export is incomplete");
+    }
+    let json = serde_json::to_string_pretty(&states)?;
+    fs::write(path, json + "\n").with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct SpaceSummary<'a> {
+    index: u32,
+    label: &'a str,
+    display: u32,
+    windows: usize,
+    visible: bool,
+    focused: bool,
+}
+
+pub fn list_spaces(json: bool, display: Option<u32>) -> Result<()> {
+    let states = query()?;
+    let spaces: Vec<SpaceSummary> = spaces_on_display(&states, display)
+        .into_iter()
+        .map(|space| SpaceSummary {
+            index: space.index,
+            label: &space.label,
+            display: space.display,
+            windows: space.windows.len(),
+            visible: space.is_visible,
+            focused: space.has_focus,
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string(&spaces)?);
+        return Ok(());
+    }
+    for space in spaces.iter() {
+        let mut flags = Vec::new();
+        if space.focused {
+            flags.push("focused");
+        }
+        if space.visible {
+            flags.push("visible");
+        }
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            space.index,
+            space.label,
+            space.display,
+            space.windows,
+            flags.join(",")
+        );
+    }
+    Ok(())
+}
+
+// Where in the destination space a moved window goes.
+#[derive(Debug, Copy, Clone)]
+enum Placement {
+    Insert(InsertArg),
+    // Next to this window.
+    After(u32),
+}
+
+// Send the focused window to a space, picked the way focus-space picks
+// one. Only that one space, never its partner.
+pub fn move_window(space: SpaceArg, insert: Option<InsertArg>) -> Result<()> {
+    let states = query()?;
+    let states = restore_if_necessary(states)?;
+    let display_uuid = states.display_uuid(
+        states
+            .focused_display_index()
+            .context("No focused display")?,
+    );
+    let focused_label_index = focused_label_index(&states, &display_uuid)?;
+    let label_index = resolve_focus_target(&states, space, focused_label_index, &display_uuid)?;
+    place_window(&states, label_index, insert.map(Placement::Insert))
+}
+
+pub fn send_window(space: u32, after: u32) -> Result<()> {
+    let states = query()?;
+    let states = restore_if_necessary(states)?;
+    place_window(&states, space, Some(Placement::After(after)))
+}
+
+fn place_window(states: &YabaiStates, space: u32, placement: Option<Placement>) -> Result<()> {
+    let window = states.focused_window().context("No focused window")?;
+    let window_id = window.id;
+    let label = format!("s{}", space);
+    if states.find_space_by_label(&label).is_none() {
+        bail!("Space {} not found", label);
+    }
+    if !window.can_move {
+        bail!("{} ({}) can't be moved", window.app, window_id);
+    }
+    move_window_to_space(&window_id, &label)?;
+
+    if let Some(placement) = placement {
+        // The window lands wherever yabai's insertion point is. Warp it
+        // next to the requested window on arrival.
+        let states = query()?;
+        let space = states
+            .find_space_by_label(&label)
+            .with_context(|| format!("Space {} not found", label))?;
+        let target = match placement {
+            Placement::After(id) => {
+                if states.find_window_id_in_space(&label, &id).is_none() {
+                    warn!(
+                        "Window {} is not on {}, leaving {} where it landed",
+                        id, label, window_id
+                    );
+                }
+                Some(id)
+            }
+            Placement::Insert(InsertArg::First) => Some(space.first_window),
+            Placement::Insert(InsertArg::Last) => Some(space.last_window),
+            Placement::Insert(InsertArg::Largest) => space
+                .windows
+                .iter()
+                .filter(|&&id| id != window_id)
+                .filter_map(|id| states.find_window(id))
+                .max_by(|a, b| a.frame.area().total_cmp(&b.frame.area()))
+                .map(|window| window.id),
+        };
+        // first-window and last-window can be stale, double check them
+        // against the windows array.
+        if let Some(target) =
+            target.filter(|&id| id != window_id && space.find_window_id(&id).is_some())
+        {
+            yabai_message(&[
+                "window",
+                &window_id.to_string(),
+                "--warp",
+                &target.to_string(),
+            ])?;
+        }
+    }
+
+    let states = query()?;
+    save_states(&states)?;
+    Ok(())
+}
+
+// Send the focused window to the visible space of the display focus was on
+// before, going by the recently focused windows.
+pub fn send_to_recent_display() -> Result<()> {
+    let states = query()?;
+    let states = restore_if_necessary(states)?;
+
+    let window = states.focused_window().context("No focused window")?;
+    if !window.can_move {
+        bail!("{} ({}) can't be moved", window.app, window.id);
+    }
+    let ctl = states::load_yabaictl_or_default()?;
+    let display = ctl
+        .recent_windows
+        .iter()
+        .filter_map(|window_id| states.find_window(window_id))
+        .map(|recent| recent.display)
+        .find(|&display| display != window.display)
+        .context("No recently focused window on another display")?;
+    let space = states
+        .spaces
+        .iter()
+        .find(|space| space.display == display && space.is_visible)
+        .with_context(|| format!("No visible space on display {}", display))?;
+    if space.label.is_empty() {
+        // Most likely an app gone fullscreen. Let yabai pick the space.
+        yabai_message(&[
+            "window",
+            &window.id.to_string(),
+            "--display",
+            &display.to_string(),
+        ])?;
+    } else {
+        move_window_to_space(&window.id, &space.label)?;
+    }
+
+    let states = query()?;
+    save_states(&states)?;
+    Ok(())
+}
+
+// The layout as the comment in space_label() draws it, from the live
+// states: one block per display with the labels, the macOS desktops and the
+// window counts. * marks the focused space, + the visible ones.
+fn render_diagram(states: &YabaiStates) -> String {
+    let mut displays: Vec<u32> = states.spaces.iter().map(|space| space.display).collect();
+    displays.sort_unstable();
+    displays.dedup();
+
+    let mut out = String::new();
+    for display in displays {
+        let spaces: Vec<&Space> = states
+            .spaces
+            .iter()
+            .filter(|space| space.display == display)
+            .collect();
+        let mut rows = [Vec::new(), Vec::new(), Vec::new()];
+        for space in spaces.iter() {
+            let label = if space.label.is_empty() {
+                "-".to_string()
+            } else {
+                space.label.clone()
+            };
+            let marker = if space.has_focus {
+                "*"
+            } else if space.is_visible {
+                "+"
+            } else {
+                ""
+            };
+            let desktop = if space.index == 1 {
+                "Desktop1".to_string()
+            } else {
+                format!("D{}", space.index)
+            };
+            rows[0].push(format!("{}{}", label, marker));
+            rows[1].push(desktop);
+            rows[2].push(space.windows.len().to_string());
+        }
+        let widths: Vec<usize> = (0..spaces.len())
+            .map(|i| rows.iter().map(|row| row[i].len()).max().unwrap_or(0))
+            .collect();
+
+        let pinned = if states.pinned_display == Some(display) {
+            " (pinned)"
+        } else {
+            ""
+        };
+        out.push_str(&format!("Display {}{}:\n\n", display, pinned));
+        for (row, legend) in rows.iter().zip(["labels", "desktops", "windows"]) {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(widths.iter())
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            out.push_str(&format!("{}  <= {}\n", cells.join(" "), legend));
+        }
+        out.push('\n');
+    }
+    if states.composite_displays() >= 2 {
+        let desktops: Vec<String> = (1..=num_spaces() / 2)
+            .map(|desktop| format!("s{}+s{}", desktop * 2 - 1, desktop * 2))
+            .collect();
+        out.push_str(&format!("Composite desktops: {}\n", desktops.join(" ")));
+    }
+    out
+}
+
+pub fn diagram() -> Result<()> {
+    let states = query()?;
+    print!("{}", render_diagram(&states));
+    Ok(())
+}
+
+// One line per choice, "id<TAB>description". --list prints them for an
+// external picker, which hands the picked line back through --pick.
+// Otherwise they are numbered on stderr and the number read from stdin.
+fn select(choices: &[(String, String)], list: bool, pick: Option<&str>) -> Result<Option<String>> {
+    if list {
+        for (id, text) in choices.iter() {
+            println!("{}\t{}", id, text);
+        }
+        return Ok(None);
+    }
+    if let Some(pick) = pick {
+        let id = pick.split('\t').next().unwrap_or_default().trim();
+        if !choices.iter().any(|(choice, _)| choice == id) {
+            bail!("{:?} is not one of the choices", id);
+        }
+        return Ok(Some(id.to_string()));
+    }
+    if choices.is_empty() {
+        bail!("Nothing to select");
+    }
+    for (i, (_, text)) in choices.iter().enumerate() {
+        eprintln!("{:>3}) {}", i + 1, text);
+    }
+    eprint!("> ");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let number: usize = line
+        .trim()
+        .parse()
+        .with_context(|| format!("Not a number: {:?}", line.trim()))?;
+    match number.checked_sub(1).and_then(|i| choices.get(i)) {
+        None => bail!("No choice {}", number),
+        Some((id, _)) => Ok(Some(id.clone())),
+    }
+}
+
+pub fn select_space(list: bool, pick: Option<&str>) -> Result<()> {
+    let states = query_warm()?;
+    let mut spaces: Vec<&Space> = states
+        .spaces
+        .iter()
+        .filter(|space| space.label_index().is_some())
+        .collect();
+    spaces.sort_by_key(|space| space.label_index());
+    let choices: Vec<(String, String)> = spaces
+        .iter()
+        .map(|space| {
+            let apps: Vec<&str> = space
+                .windows
+                .iter()
+                .filter_map(|window_id| states.find_window(window_id))
+                .map(|window| window.app.as_str())
+                .collect();
+            (
+                space.label_index().unwrap().to_string(),
+                format!("{}\t{}", space.label, apps.join(", ")),
+            )
+        })
+        .collect();
+    match select(&choices, list, pick)? {
+        None => Ok(()),
+        Some(id) => focus_space(SpaceArg::Space(id.parse()?), false),
+    }
+}
+
+pub fn select_window(list: bool, pick: Option<&str>) -> Result<()> {
+    let states = query_warm()?;
+    let choices: Vec<(String, String)> = states
+        .windows
+        .iter()
+        .filter(|window| !window.is_minimized && !window.is_hidden)
+        .map(|window| {
+            let label = states
+                .spaces
+                .iter()
+                .find(|space| space.find_window_id(&window.id).is_some())
+                .map_or("", |space| space.label.as_str());
+            (
+                window.id.to_string(),
+                format!("{}\t{}\t{}", window.app, window.title, label),
+            )
+        })
+        .collect();
+    match select(&choices, list, pick)? {
+        None => Ok(()),
+        Some(id) => {
+            focus_window_composite(&states, id.parse()?)?;
+            let states = query()?;
+            save_states(&states)?;
+            Ok(())
+        }
+    }
+}
+
+pub fn wait_visible(space: u32, timeout: Duration) -> Result<()> {
+    let label = format!("s{}", space);
+    let deadline = Instant::now() + timeout;
+    loop {
+        let spaces: Vec<Space> = yabai_query(QueryDomain::Spaces)?;
+        match spaces.iter().find(|space| space.label == label) {
+            None => bail!("Space {} not found", label),
+            Some(space) if space.is_visible => return Ok(()),
+            Some(_) => {}
+        }
+        if Instant::now() > deadline {
+            bail!("Timed out waiting for {} to become visible", label);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+pub fn partner(space: u32) -> Result<()> {
+    let states = query()?;
+    let partner_space = if states.composite_displays() < 2 || space == 0 || space > num_spaces() {
+        None
+    } else {
+        states.find_space_by_label_index(partner_label_index(space))
+    };
+    match partner_space {
+        None => println!("none"),
+        Some(partner_space) => println!("{}", partner_space.label),
+    }
+    Ok(())
+}
+
+pub fn focus_desktop(desktop: u32, side: SideArg, alone: bool) -> Result<()> {
+    let states = query_settled()?;
+    if desktop == 0 || desktop > num_spaces() / 2 {
+        bail!("Desktop {} out of range", desktop);
+    }
+    // Composite desktop n is s(2n-1) on the left monitor and s(2n) on the
+    // right one.
+    let label_index = match (states.composite_displays(), side) {
+        (1, _) => desktop,
+        (_, SideArg::Left) => desktop * 2 - 1,
+        (_, SideArg::Right) => desktop * 2,
+    };
+    if !alone {
+        return focus_space(SpaceArg::Space(label_index), false);
+    }
+    restore_if_necessary(states)?;
+    focus_space_by_label(label_index)?;
+    let states = query()?;
+    save_states(&states)?;
+    Ok(())
+}
+
+pub fn regroup_space(space: u32, group: u32) -> Result<()> {
+    let states = query_warm()?;
+    let states = restore_if_necessary(states)?;
+
+    if space == 0 || space > num_spaces() {
+        bail!("Space {} out of range", space);
+    }
+    let num_groups = if states.composite_displays() >= 2 {
+        num_spaces() / 2
+    } else {
+        num_spaces()
+    };
+    if group == 0 || group > num_groups {
+        bail!("Group {} out of range", group);
+    }
+
+    // With two or more monitors, group n is the composite desktop made of
+    // s(2n-1) on the left and s(2n) on the right. The space stays on its own
+    // monitor, so it keeps its parity and only changes its pair.
+    let target_label_index = match states.composite_displays() {
+        1 => group,
+        _ => {
+            if space.is_multiple_of(2) {
+                group * 2
             } else {
-                focused_label_index - display_count
+                group * 2 - 1
             }
         }
-        SpaceArg::Third => 11,
-        SpaceArg::Fourth => 12,
-        SpaceArg::Space(number) => number,
     };
-    eprintln!("focus_space: label_index={}", label_index);
-    match states.num_displays() {
-        1 => {
-            focus_space_by_label(label_index)?;
+    if target_label_index == space {
+        info!("s{} is already in group {}", space, group);
+        return Ok(());
+    }
+
+    let source = states
+        .find_space_by_label_index(space)
+        .with_context(|| format!("Space s{} not found", space))?;
+    let target = states
+        .find_space_by_label_index(target_label_index)
+        .with_context(|| format!("Space s{} not found", target_label_index))?;
+
+    // The labels go by position, so restore would undo a label swap on the
+    // next command. Trade the windows with the space in the slot instead,
+    // which keeps every pair with one space on each monitor.
+    let source_windows = movable_windows(&states, source);
+    let target_windows = movable_windows(&states, target);
+    let found = states
+        .windows
+        .iter()
+        .any(|window| window.space == source.index);
+    if !source.windows.is_empty() && !found {
+        bail!("None of the windows of s{} were found", space);
+    }
+    for window_id in source_windows.iter() {
+        move_window_to_space(window_id, &target.label)?;
+    }
+    for window_id in target_windows.iter() {
+        move_window_to_space(window_id, &source.label)?;
+    }
+
+    let states = query()?;
+    save_states(&states)?;
+    Ok(())
+}
+
+// Going by Window.space, which is right even when the windows array of the
+// space isn't.
+fn movable_windows(states: &YabaiStates, space: &Space) -> Vec<u32> {
+    states
+        .windows
+        .iter()
+        .filter(|window| window.space == space.index && window.can_move)
+        .map(|window| window.id)
+        .collect()
+}
+
+// Trade the spaces of the two composite displays. Every pair keeps its
+// windows together, only the halves change monitors.
+pub fn swap_monitors() -> Result<()> {
+    if is_labels_only() {
+        bail!("Moving spaces needs the scripting addition");
+    }
+    let states = query_settled()?;
+    let states = restore_if_necessary(states)?;
+    if states.composite_displays() != 2 || states.pinned_display.is_some() {
+        bail!("swap-monitors needs exactly two displays and no pinned display");
+    }
+    let focused_window = states.focused_window().map(|window| window.id);
+
+    for (label, display) in swap_moves(&states) {
+        move_space_to_display(&label, display)?;
+    }
+
+    // The labels go by position, so they change hands as well. Clear them
+    // out of the way first to keep them unique while relabeling.
+    let states = query()?;
+    for space in states.spaces.iter() {
+        if space.label_index().is_some() {
+            label_space(space.index, &format!("swap{}", space.index))?;
         }
-        _ => {
-            // This is to bring both desktops to focus
-            let neighbor_label_index = match label_index % 2 {
-                0 => label_index - 1,
-                _ => label_index + 1,
+    }
+    let states = query()?;
+    even_spaces(&states)?;
+    let states = ensure_labels(&query()?)?;
+
+    if let Some(window_id) = focused_window {
+        focus_window_composite(&states, window_id)?;
+    }
+    let states = query()?;
+    save_states(&states)?;
+    Ok(())
+}
+
+// Where swap-monitors sends each labeled space: to the display its partner
+// is on now, whichever side the odd labels are on. The two halves of a pair
+// go one after the other, so neither display is ever left without a space.
+fn swap_moves(states: &YabaiStates) -> Vec<(String, u32)> {
+    let mut moves = Vec::new();
+    for label_index in 1..=num_spaces() {
+        let partner = match states.find_space_by_label_index(partner_label_index(label_index)) {
+            None => continue,
+            Some(partner) => partner,
+        };
+        if states.find_space_by_label_index(label_index).is_some() {
+            moves.push((format!("s{}", label_index), partner.display));
+        }
+    }
+    moves
+}
+
+fn composite_spaces(states: &YabaiStates) -> Vec<&Space> {
+    let mut spaces = Vec::new();
+    let focused_space = match states.focused_space() {
+        None => return spaces,
+        Some(space) => space,
+    };
+    spaces.push(focused_space);
+    if states.composite_displays() < 2 {
+        return spaces;
+    }
+    if let Some(label_index) = focused_space.label_index() {
+        let neighbor_label_index = partner_label_index(label_index);
+        if let Some(neighbor_space) = states.find_space_by_label_index(neighbor_label_index) {
+            spaces.push(neighbor_space);
+        }
+    }
+    spaces
+}
+
+// What apply-to-composite does to every window, with the value only where
+// the op takes one.
+#[derive(Debug, PartialEq)]
+enum CompositeAction {
+    Float,
+    Unfloat,
+    Opacity(f32),
+    Minimize,
+}
+
+fn composite_action(op: CompositeOp, value: Option<f32>) -> Result<CompositeAction> {
+    if op != CompositeOp::Opacity && value.is_some() {
+        bail!("Only opacity takes a value");
+    }
+    Ok(match op {
+        CompositeOp::Float => CompositeAction::Float,
+        CompositeOp::Unfloat => CompositeAction::Unfloat,
+        CompositeOp::Minimize => CompositeAction::Minimize,
+        CompositeOp::Opacity => match value {
+            None => bail!("opacity requires a value between 0.0 and 1.0"),
+            Some(opacity) if !(0.0..=1.0).contains(&opacity) => {
+                bail!("Opacity {} is not between 0.0 and 1.0", opacity)
+            }
+            Some(opacity) => CompositeAction::Opacity(opacity),
+        },
+    })
+}
+
+pub fn apply_to_composite(op: CompositeOp, value: Option<f32>) -> Result<()> {
+    let states = query_warm()?;
+    let states = restore_if_necessary(states)?;
+
+    let action = composite_action(op, value)?;
+
+    for space in composite_spaces(&states) {
+        for window_id in space.windows.iter() {
+            let window = match states.find_window(window_id) {
+                None => continue,
+                Some(window) => window,
             };
-            let neighbor_space = states.find_space_by_label_index(neighbor_label_index);
-            match neighbor_space {
-                None => {}
-                Some(neighbor_space) => {
-                    // Skip bringing the other screen to focus if it is already in focus or visible
-                    if focused_label_index != neighbor_label_index && !neighbor_space.is_visible {
-                        focus_space_by_label(neighbor_label_index)?;
-                    }
+            let id = window_id.to_string();
+            let r = match action {
+                CompositeAction::Float if !window.is_floating => {
+                    yabai_message(&["window", &id, "--toggle", "float"])
+                }
+                CompositeAction::Unfloat if window.is_floating => {
+                    yabai_message(&["window", &id, "--toggle", "float"])
+                }
+                CompositeAction::Opacity(opacity) => {
+                    yabai_message(&["window", &id, "--opacity", &opacity.to_string()])
+                }
+                CompositeAction::Minimize if !window.is_minimized => {
+                    yabai_message(&["window", &id, "--minimize"])
+                }
+                _ => continue,
+            };
+            if let Err(e) = r {
+                if !e
+                    .to_string()
+                    .contains("could not locate the window to act on!")
+                {
+                    return Err(e);
                 }
+                info!("Skipping {}. It no longer exists", window_id);
             }
-            focus_space_by_label(label_index)?;
         }
     }
 
-    let ctl = &YabaictlStates {
-        recent: focused_label_index,
-    };
-    states::save_yabaictl(ctl)?;
     let states = query()?;
-    states::save_yabai(&states)?;
+    save_states(&states)?;
     Ok(())
 }
 
-pub fn operate_window(op: WindowOp, direction: WindowArg) -> Result<()> {
+// With wrap, the op never crosses over to the other monitor.
+pub fn operate_window(
+    op: WindowOp,
+    direction: WindowArg,
+    wrap: bool,
+    window: Option<u32>,
+) -> Result<()> {
+    let edge_modes = &config::get().edge_modes;
+    let edge_mode = match op {
+        WindowOp::Focus => edge_modes.focus,
+        WindowOp::Swap => edge_modes.swap,
+        WindowOp::Warp => edge_modes.warp,
+    };
+    let wrap = wrap || edge_mode == EdgeMode::Wrap;
     let states = query()?;
     let states = restore_if_necessary(states)?;
+    let states = ensure_focused_display_labeled(states)?;
+    // Everything below goes from the focused window, so start by focusing
+    // the one asked for.
+    let states = match window {
+        Some(window_id) => {
+            if states.find_window(&window_id).is_none() {
+                bail!("Window {} not found", window_id);
+            }
+            focus_window_composite(&states, window_id)?;
+            query()?
+        }
+        None => states,
+    };
+    let focused_window = states.focused_window().map(|window| window.id);
 
     let r = yabai_message(&["window", op.as_str(), direction.as_str()]);
-    match r {
-        Err(e) => {
-            match direction {
-                WindowArg::East => {}
-                WindowArg::West => {}
-                _ => {
-                    return Err(e);
-                }
-            }
-            let e_str = e.to_string();
-            let expected1 = format!(
-                "could not locate a {}ward managed window.",
-                direction.as_str()
-            );
-            // This is the error when the space has no windows
-            let expected2 = "could not locate the selected window.";
-            if !e_str.contains(&expected1) && !e_str.contains(&expected2) {
+    if let Err(e) = r {
+        match direction {
+            WindowArg::East => {}
+            WindowArg::West => {}
+            _ => {
                 return Err(e);
             }
+        }
+        let e_str = e.to_string();
+        let expected1 = format!(
+            "could not locate a {}ward managed window.",
+            direction.as_str()
+        );
+        // This is the error when the space has no windows
+        let expected2 = "could not locate the selected window.";
+        if !e_str.contains(&expected1) && !e_str.contains(expected2) {
+            return Err(e);
+        }
 
-            match states.num_displays() {
-                1 => {
-                    let space = states.focused_space().expect("No focused space found");
-                    let next_window = match direction {
-                        WindowArg::East => space.first_window,
-                        WindowArg::West => space.last_window,
-                        _ => {
-                            return Err(e);
-                        }
-                    };
-                    yabai_message(&["window", op.as_str(), &next_window.to_string()])?;
-                }
-                _ => {
-                    let neighbor_space = neighbor_space(&states, direction);
-                    let neighbor_space = match neighbor_space {
-                        None => {
-                            return Err(e);
-                        }
-                        Some(space) => space,
-                    };
+        match states.composite_displays() {
+            displays if displays == 1 || wrap => {
+                let space = states.focused_space().expect("No focused space found");
+                let next_window = match direction {
+                    WindowArg::East => space.first_window,
+                    WindowArg::West => space.last_window,
+                    _ => {
+                        return Err(e);
+                    }
+                };
+                yabai_message(&["window", op.as_str(), &next_window.to_string()])?;
+            }
+            _ => {
+                let neighbor_space = neighbor_space(&states, direction);
+                let neighbor_space = match neighbor_space {
+                    None => {
+                        return Err(e);
+                    }
+                    Some(space) => space,
+                };
 
-                    match op {
-                        WindowOp::Focus => {
+                match op {
+                    WindowOp::Focus => {
+                        let next_window = match direction {
+                            WindowArg::East => neighbor_space.first_window,
+                            WindowArg::West => neighbor_space.last_window,
+                            _ => {
+                                return Err(e);
+                            }
+                        };
+                        let next_window = if next_window == 0
+                            // Sometimes yabai's first-window and
+                            // last-window states get stale.  Verify that
+                            // the window is still in the windows array for
+                            // the space. If it is not, most likely the
+                            // space is empty with a hidden window or two.
+                            || neighbor_space.find_window_id(&next_window).is_none()
+                        {
+                            let space = states.focused_space().expect("No focused space found");
+                            match direction {
+                                WindowArg::East => space.first_window,
+                                WindowArg::West => space.last_window,
+                                _ => {
+                                    return Err(e);
+                                }
+                            }
+                        } else {
+                            next_window
+                        };
+                        debug!("next_window={}", next_window);
+                        yabai_message(&["window", op.as_str(), &next_window.to_string()])?;
+                    }
+                    WindowOp::Swap | WindowOp::Warp => {
+                        if neighbor_space.windows.is_empty() {
+                            // If the neighbor space is empty, just send the
+                            // window there
+                            yabai_message(&["window", "--space", &neighbor_space.label])?;
+                        } else {
                             let next_window = match direction {
                                 WindowArg::East => neighbor_space.first_window,
                                 WindowArg::West => neighbor_space.last_window,
@@ -574,53 +3482,676 @@ pub fn operate_window(op: WindowOp, direction: WindowArg) -> Result<()> {
                                     return Err(e);
                                 }
                             };
-                            let next_window = if next_window == 0
-                                // Sometimes yabai's first-window and
-                                // last-window states get stale.  Verify that
-                                // the window is still in the windows array for
-                                // the space. If it is not, most likely the
-                                // space is empty with a hidden window or two.
-                                || neighbor_space.find_window_id(&next_window).is_none()
-                            {
-                                let space = states.focused_space().expect("No focused space found");
-                                match direction {
-                                    WindowArg::East => space.first_window,
-                                    WindowArg::West => space.last_window,
-                                    _ => {
-                                        return Err(e);
-                                    }
-                                }
-                            } else {
-                                next_window
-                            };
-                            eprintln!("next_window={}", next_window);
                             yabai_message(&["window", op.as_str(), &next_window.to_string()])?;
                         }
-                        WindowOp::Swap | WindowOp::Warp => {
-                            if neighbor_space.windows.len() == 0 {
-                                // If the neighbor space is empty, just send the
-                                // window there
-                                yabai_message(&["window", "--space", &neighbor_space.label])?;
-                            } else {
-                                let next_window = match direction {
-                                    WindowArg::East => neighbor_space.first_window,
-                                    WindowArg::West => neighbor_space.last_window,
-                                    _ => {
-                                        return Err(e);
-                                    }
-                                };
-                                yabai_message(&["window", op.as_str(), &next_window.to_string()])?;
-                            }
 
-                            yabai_message(&["space", "--focus", &neighbor_space.label])?;
+                        yabai_message(&["space", "--focus", &neighbor_space.label])?;
+                        // Focusing the space leaves it to yabai which of its
+                        // windows gets focus. Follow the window that moved.
+                        if let Some(window_id) = focused_window {
+                            yabai_message(&["window", "--focus", &window_id.to_string()])?;
                         }
-                    };
-                }
+                    }
+                };
             }
         }
-        Ok(_) => {}
     }
     let states = query()?;
-    states::save_yabai(&states)?;
+    if config::get().center_mouse {
+        center_mouse(&states);
+    }
+    save_states(&states)?;
+    Ok(())
+}
+
+// With focus follows mouse, the pointer left behind on the other monitor
+// takes focus right back as soon as it moves. Put it on the focused window
+// instead.
+fn center_mouse(states: &YabaiStates) {
+    let frame = match states.focused_window() {
+        None => return,
+        Some(window) => &window.frame,
+    };
+    if frame.area() == 0.0 {
+        return;
+    }
+    let (x, y) = frame.center();
+    if is_dry_run() {
+        println!("warp mouse to {},{}", x, y);
+        return;
+    }
+    warp_mouse(x, y);
+}
+
+// yabai's frames are in the same global coordinates as CoreGraphics.
+#[cfg(target_os = "macos")]
+fn warp_mouse(x: f32, y: f32) {
+    #[repr(C)]
+    struct CGPoint {
+        x: f64,
+        y: f64,
+    }
+    #[link(name = "CoreGraphics", kind = "framework")]
+    extern "C" {
+        fn CGWarpMouseCursorPosition(point: CGPoint) -> i32;
+    }
+    let point = CGPoint {
+        x: x.into(),
+        y: y.into(),
+    };
+    unsafe {
+        CGWarpMouseCursorPosition(point);
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn warp_mouse(_x: f32, _y: f32) {}
+
+// Quote for a POSIX shell, so app names with spaces or quotes survive the
+// yabairc.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+// Anchor the app name, since yabai matches app= as a regex.
+fn app_regex(app: &str) -> String {
+    let mut regex = String::from("^");
+    for c in app.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            regex.push('\\');
+        }
+        regex.push(c);
+    }
+    regex.push('$');
+    regex
+}
+
+fn placement_rules(
+    placements: &BTreeMap<String, String>,
+    pinned_labels: &[String],
+) -> Result<Vec<String>> {
+    let mut rules = Vec::new();
+    for (app, label) in placements.iter() {
+        let known = match label.strip_prefix('s').map(str::parse::<u32>) {
+            Some(Ok(index)) => (1..=num_spaces()).contains(&index),
+            _ => pinned_labels.contains(label),
+        };
+        if !known {
+            bail!(
+                "{} is placed on {}, which yabaictl never labels",
+                app,
+                label
+            );
+        }
+        rules.push(format!(
+            "yabai -m rule --add app={} space={}",
+            shell_quote(&app_regex(app)),
+            label
+        ));
+    }
+    Ok(rules)
+}
+
+// Print yabai rules that put every app of placements on its space, for
+// those who would rather have yabai do it than restore.
+pub fn generate_rules() -> Result<()> {
+    let config = config::get();
+    let pinned_labels = match &config.pinned_display {
+        Some(pinned) => pinned.labels.clone(),
+        None => Vec::new(),
+    };
+    for rule in placement_rules(&config.placements, &pinned_labels)? {
+        println!("{}", rule);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::fixture;
+
+    #[test]
+    fn encode_message_wire_format() {
+        let message = encode_message(&["query", "--spaces"]).unwrap();
+        let mut expected = vec![16, 0, 0, 0];
+        expected.extend_from_slice(b"query\0--spaces\0\0");
+        assert_eq!(message, expected);
+    }
+
+    #[test]
+    fn encode_message_without_args() {
+        assert_eq!(encode_message(&[]).unwrap(), vec![1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encode_message_empty_arg() {
+        assert_eq!(encode_message(&[""]).unwrap(), vec![2, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            encode_message(&["space", ""]).unwrap(),
+            vec![8, 0, 0, 0, b's', b'p', b'a', b'c', b'e', 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn encode_message_arg_with_spaces() {
+        // Spaces are part of the argument, only null bytes separate them.
+        let message = encode_message(&["rule", "--add", "app=Google Chrome"]).unwrap();
+        let mut expected = vec![30, 0, 0, 0];
+        expected.extend_from_slice(b"rule\0--add\0app=Google Chrome\0\0");
+        assert_eq!(message, expected);
+    }
+
+    #[test]
+    fn encode_message_length_counts_bytes() {
+        let message = encode_message(&["space", "--label", "é"]).unwrap();
+        assert_eq!(&message[..4], &[18, 0, 0, 0]);
+        assert_eq!(message.len(), 4 + 18);
+    }
+
+    #[test]
+    fn transient_space_errors() {
+        assert!(is_transient_space_error(&anyhow::anyhow!(
+            "cannot connect to scripting-addition socket."
+        )));
+        assert!(!is_transient_space_error(&anyhow::anyhow!(
+            "cannot destroy the last space on a display."
+        )));
+    }
+
+    #[test]
+    fn visible_windows_in_display_order() {
+        let states = fixture("two_displays");
+        assert_eq!(visible_window_cycle(&states), [201, 202, 301]);
+    }
+
+    #[test]
+    fn pairing_problems_name_the_label() {
+        let mut states = fixture("two_displays");
+        assert!(pairing_problems(&states).is_empty());
+        states.spaces.retain(|space| space.label != "s10");
+        assert_eq!(pairing_problems(&states), ["s9 has no partner s10"]);
+        for space in states.spaces.iter_mut() {
+            if space.label == "s3" {
+                space.display = 1;
+            }
+        }
+        assert_eq!(
+            pairing_problems(&states),
+            ["s3 and s4 are both on display 1", "s9 has no partner s10"]
+        );
+    }
+
+    #[test]
+    fn socket_candidates_in_order() {
+        assert_eq!(
+            socket_candidates(
+                Some(OsString::from("/run/yabai.socket")),
+                Some("/tmp/custom.socket"),
+                Some(OsString::from("me")),
+            ),
+            [
+                PathBuf::from("/run/yabai.socket"),
+                PathBuf::from("/tmp/custom.socket"),
+                PathBuf::from("/tmp/yabai_me.socket"),
+            ]
+        );
+        assert_eq!(
+            socket_candidates(Some(OsString::new()), None, None),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[test]
+    fn neighbor_space_goes_by_frames() {
+        // s2 is focused on display 1, which is right of display 2 with s1.
+        let states = fixture("two_displays");
+        assert_eq!(
+            neighbor_space(&states, WindowArg::West).map(|space| space.label.as_str()),
+            Some("s1")
+        );
+        assert!(neighbor_space(&states, WindowArg::East).is_none());
+        assert!(neighbor_space(&states, WindowArg::North).is_none());
+    }
+
+    #[test]
+    fn desktop_numbers() {
+        assert_eq!(desktop_number(1, 2), Some(1));
+        assert_eq!(desktop_number(2, 2), Some(1));
+        assert_eq!(desktop_number(9, 2), Some(5));
+        assert_eq!(desktop_number(10, 2), Some(5));
+        assert_eq!(desktop_number(7, 1), Some(7));
+        assert_eq!(desktop_number(0, 2), None);
+        assert_eq!(desktop_number(11, 2), None);
+    }
+
+    #[test]
+    fn yabai_versions() {
+        assert_eq!(parse_yabai_version("yabai-v7.1.1\n"), Some((7, 1, 1)));
+        assert_eq!(parse_yabai_version("yabai-v4.0"), Some((4, 0, 0)));
+        assert_eq!(parse_yabai_version("yabai-vx"), None);
+        assert!(is_tested_version((4, 0, 4)));
+        assert!(is_tested_version((7, 1, 16)));
+        assert!(!is_tested_version((3, 3, 10)));
+        assert!(!is_tested_version((7, 2, 0)));
+    }
+
+    #[test]
+    fn placement_rules_quote_and_anchor() {
+        let mut placements = BTreeMap::new();
+        placements.insert("Google Chrome".to_string(), "s2".to_string());
+        placements.insert("Bob's App.app".to_string(), "mail".to_string());
+        let rules = placement_rules(&placements, &["mail".to_string()]).unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                "yabai -m rule --add app='^Bob'\\''s App\\.app$' space=mail",
+                "yabai -m rule --add app='^Google Chrome$' space=s2",
+            ]
+        );
+    }
+
+    #[test]
+    fn live_destinations_follow_placements() {
+        let states = fixture("two_displays");
+        let mut placements = BTreeMap::new();
+        placements.insert("Safari".to_string(), "s1".to_string());
+        placements.insert("Slack".to_string(), "s1".to_string());
+        placements.insert("Terminal".to_string(), "s42".to_string());
+        assert_eq!(
+            live_destinations(&states, &placements, "s1"),
+            [(202, "s1".to_string())]
+        );
+    }
+
+    #[test]
+    fn placement_rules_unknown_label() {
+        let mut placements = BTreeMap::new();
+        placements.insert("Slack".to_string(), "s11".to_string());
+        assert!(placement_rules(&placements, &[]).is_err());
+        placements.insert("Slack".to_string(), "mail".to_string());
+        assert!(placement_rules(&placements, &[]).is_err());
+    }
+
+    #[test]
+    fn merge_renames_appends_unplugged_display() {
+        let old_states = fixture("two_displays");
+        let mut states = fixture("two_displays");
+        states
+            .displays
+            .retain(|display| display.uuid == "RIGHT-UUID");
+        let renames: Vec<String> = merge_renames(&old_states, &states)
+            .into_iter()
+            .map(|(old_label, new_label)| format!("{}>{}", old_label, new_label))
+            .collect();
+        assert_eq!(
+            renames,
+            vec![
+                "s2>s1", "s4>s2", "s6>s3", "s8>s4", "s10>s5", "s1>s6", "s3>s7", "s5>s8", "s7>s9",
+                "s9>s10",
+            ]
+        );
+        assert!(merge_renames(&old_states, &old_states).is_empty());
+    }
+
+    fn labels(plan: Vec<(u32, String)>) -> Vec<String> {
+        plan.into_iter().map(|(_, label)| label).collect()
+    }
+
+    fn label_plan(num_spaces: u32, num_displays: u32) -> Vec<(u32, String)> {
+        SpacePlan::new(num_displays, None, FallbackMode::IgnoreExtra)
+            .unwrap()
+            .labels(num_spaces)
+            .unwrap()
+    }
+
+    #[test]
+    fn label_plan_one_display() {
+        let plan = label_plan(11, 1);
+        assert_eq!(plan[0], (1, "reserved".to_string()));
+        assert_eq!(plan[1], (2, "s1".to_string()));
+        assert_eq!(plan[10], (11, "s10".to_string()));
+    }
+
+    #[test]
+    fn label_plan_one_display_any_count() {
+        // Fewer spaces than expected: every one still gets the next label.
+        assert_eq!(labels(label_plan(4, 1)), ["reserved", "s1", "s2", "s3"]);
+        // More: the labels carry on past s10 rather than stopping.
+        let plan = label_plan(14, 1);
+        assert_eq!(plan.len(), 14);
+        assert_eq!(plan[13], (14, "s13".to_string()));
+        for (space_index, label) in plan.iter().skip(1) {
+            assert_eq!(*label, format!("s{}", space_index - 1));
+        }
+    }
+
+    fn one_display(num_spaces: u32) -> YabaiStates {
+        let mut states = fixture("two_displays");
+        states.displays.truncate(1);
+        states
+            .spaces
+            .retain(|space| space.index <= num_spaces.min(11));
+        for space in states.spaces.iter_mut() {
+            space.display = 1;
+        }
+        let mut more: YabaiStates = serde_json::from_str(&format!(
+            r#"{{"spaces": [{}], "displays": [], "windows": []}}"#,
+            (12..=num_spaces)
+                .map(|index| format!(r#"{{"index": {}, "label": "", "display": 1}}"#, index))
+                .collect::<Vec<String>>()
+                .join(",")
+        ))
+        .unwrap();
+        states.spaces.append(&mut more.spaces);
+        states
+    }
+
+    #[test]
+    fn display_label_plan_one_display_any_count() {
+        let states = one_display(5);
+        assert_eq!(
+            labels(display_label_plan(&states)),
+            ["reserved", "s1", "s2", "s3", "s4"]
+        );
+        let states = one_display(14);
+        let plan = display_label_plan(&states);
+        assert_eq!(plan.len(), 14);
+        for (space_index, label) in plan.iter().skip(1) {
+            assert_eq!(*label, format!("s{}", space_index - 1));
+        }
+    }
+
+    #[test]
+    fn bootstrap_from_one_space() {
+        // A brand-new macOS account: one display with a single desktop.
+        let states = fixture("one_space");
+        assert!(!states.is_degenerate());
+        validate_space_count(num_spaces(), &SpacePlan::for_states(&states).unwrap()).unwrap();
+        let plan = SpacePlan::for_states(&states).unwrap();
+        assert_eq!(plan.target(), num_spaces() + 1);
+        assert!(misplaced_spaces(&states).unwrap().is_empty());
+        // Before the spaces are created, there is only the reserved one.
+        assert_eq!(
+            labels(plan.labels(states.num_spaces()).unwrap()),
+            ["reserved"]
+        );
+        assert_eq!(labels(display_label_plan(&states)), ["reserved"]);
+        assert!(states.focused_display_unlabeled());
+        // After, every desktop has its label.
+        let after = labels(plan.labels(plan.target()).unwrap());
+        assert_eq!(after[0], "reserved");
+        assert_eq!(
+            after[1..],
+            (1..=num_spaces())
+                .map(|i| format!("s{}", i))
+                .collect::<Vec<_>>()[..]
+        );
+    }
+
+    #[test]
+    fn label_plan_two_displays() {
+        assert_eq!(
+            labels(label_plan(11, 2)),
+            ["reserved", "s2", "s4", "s6", "s8", "s10", "s1", "s3", "s5", "s7", "s9"]
+        );
+    }
+
+    #[test]
+    fn primary_by_geometry_with_a_left_monitor() {
+        // Display 2 sits left of the menu bar display, at x=-1920.
+        let states = fixture("two_displays");
+        assert!(!primary_is_second(None, &states.displays));
+        assert!(!primary_is_second(Some("rightmost"), &states.displays));
+        assert!(primary_is_second(Some("leftmost"), &states.displays));
+        assert!(primary_is_second(Some("LEFT-UUID"), &states.displays));
+        assert!(!primary_is_second(Some("RIGHT-UUID"), &states.displays));
+        assert_eq!(states.displays_left_to_right(), [2, 1]);
+    }
+
+    #[test]
+    fn primary_side_without_frames() {
+        let mut states = fixture("two_displays");
+        assert!(!sides_swapped(None, None, &states.displays));
+        for display in states.displays.iter_mut() {
+            display.frame = Default::default();
+        }
+        assert!(!sides_swapped(None, None, &states.displays));
+        assert!(sides_swapped(
+            None,
+            Some(PrimarySide::Left),
+            &states.displays
+        ));
+        assert!(!sides_swapped(
+            None,
+            Some(PrimarySide::Right),
+            &states.displays
+        ));
+        assert!(sides_swapped(
+            Some("LEFT-UUID"),
+            Some(PrimarySide::Right),
+            &states.displays
+        ));
+    }
+
+    #[test]
+    fn space_plan_swapped_sides() {
+        let mut plan = SpacePlan::new(2, None, FallbackMode::IgnoreExtra).unwrap();
+        plan.swap_sides();
+        let labels: Vec<&str> = plan
+            .spaces
+            .iter()
+            .map(|(label, _)| label.as_str())
+            .collect();
+        assert_eq!(
+            labels,
+            ["reserved", "s1", "s3", "s5", "s7", "s9", "s2", "s4", "s6", "s8", "s10"]
+        );
+        // Only the labels trade places, the spaces stay on their displays.
+        assert_eq!(plan.spaces[1].1, 1);
+        assert_eq!(plan.spaces[6].1, 2);
+    }
+
+    #[test]
+    fn label_plan_extra_display() {
+        // The default fallback gives the third display a single space.
+        let plan = label_plan(12, 3);
+        assert_eq!(plan.len(), 12);
+        assert_eq!(plan[11], (12, "s11".to_string()));
+    }
+
+    #[test]
+    fn space_plan_any_number_of_displays() {
+        // The first two displays keep the composite desktops and the
+        // reserved space, every display after them gets a space of its own.
+        let composite = num_spaces() + 1;
+        for num_displays in 4..=5 {
+            let plan = SpacePlan::new(num_displays, None, FallbackMode::IgnoreExtra).unwrap();
+            assert_eq!(plan.target(), composite + num_displays - 2);
+            assert_eq!(plan.spaces[0], ("reserved".to_string(), 1));
+            for display in 3..=num_displays {
+                let space = &plan.spaces[(composite + display - 3) as usize];
+                assert_eq!(
+                    space,
+                    &(format!("s{}", num_spaces() + display - 2), display)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn space_plan_mirror() {
+        let n = num_spaces();
+        let plan = SpacePlan::new(5, None, FallbackMode::Mirror).unwrap();
+        // One more set of composite spaces for displays 3 and 4, and a single
+        // space for the display left over. Only display 1 has a reserved one.
+        assert_eq!(plan.target(), 2 * n + 2);
+        assert_eq!(
+            plan.spaces
+                .iter()
+                .filter(|(label, _)| label == "reserved")
+                .count(),
+            1
+        );
+        let pair = &plan.spaces[(n + 1) as usize..(2 * n + 1) as usize];
+        for (i, (label, display)) in pair.iter().enumerate() {
+            let i = i as u32;
+            let expected = if i < n / 2 {
+                (format!("s{}", n + (i + 1) * 2), 3)
+            } else {
+                (format!("s{}", n + (i - n / 2) * 2 + 1), 4)
+            };
+            assert_eq!((label.clone(), *display), expected);
+        }
+        assert_eq!(
+            plan.spaces[(2 * n + 1) as usize],
+            (format!("s{}", 2 * n + 1), 5)
+        );
+        // With no extra displays it is the plain two display scheme.
+        assert_eq!(
+            SpacePlan::new(2, None, FallbackMode::Mirror)
+                .unwrap()
+                .spaces,
+            SpacePlan::new(2, None, FallbackMode::IgnoreExtra)
+                .unwrap()
+                .spaces
+        );
+    }
+
+    #[test]
+    fn space_count_for_mirrored_displays() {
+        let plan = SpacePlan::new(4, None, FallbackMode::IgnoreExtra).unwrap();
+        assert_eq!(plan.composite_displays(), 2);
+        assert!(validate_space_count(4, &plan).is_ok());
+        let plan = SpacePlan::new(5, None, FallbackMode::Mirror).unwrap();
+        assert_eq!(plan.composite_displays(), 4);
+        assert!(validate_space_count(6, &plan).is_err());
+        assert!(validate_space_count(8, &plan).is_ok());
+    }
+
+    #[test]
+    fn space_plan_pinned_first_display() {
+        let pinned = ["chat".to_string(), "mail".to_string()];
+        let plan = SpacePlan::new(2, Some((1, &pinned)), FallbackMode::IgnoreExtra).unwrap();
+        assert_eq!(plan.target(), 13);
+        assert_eq!(plan.spaces[0], ("reserved".to_string(), 1));
+        assert_eq!(plan.spaces[1], ("chat".to_string(), 1));
+        assert_eq!(plan.spaces[2], ("mail".to_string(), 1));
+        // The external display alone gets the single display scheme.
+        assert_eq!(plan.spaces[3], ("s1".to_string(), 2));
+        assert_eq!(plan.spaces[12], ("s10".to_string(), 2));
+    }
+
+    #[test]
+    fn space_plan_pinned_between_composite_displays() {
+        let pinned = ["chat".to_string()];
+        let plan = SpacePlan::new(3, Some((2, &pinned)), FallbackMode::IgnoreExtra).unwrap();
+        let displays: Vec<(&str, u32)> = plan
+            .spaces
+            .iter()
+            .map(|(label, display)| (label.as_str(), *display))
+            .collect();
+        assert_eq!(
+            displays,
+            [
+                ("reserved", 1),
+                ("s2", 1),
+                ("s4", 1),
+                ("s6", 1),
+                ("s8", 1),
+                ("s10", 1),
+                ("chat", 2),
+                ("s1", 3),
+                ("s3", 3),
+                ("s5", 3),
+                ("s7", 3),
+                ("s9", 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn space_plan_reserved_on_second_display() {
+        let mut plan = SpacePlan::new(2, None, FallbackMode::IgnoreExtra).unwrap();
+        plan.move_reserved(2);
+        assert_eq!(plan.target(), 11);
+        assert_eq!(plan.spaces[0], ("s2".to_string(), 1));
+        assert_eq!(plan.spaces[4], ("s10".to_string(), 1));
+        assert_eq!(plan.spaces[5], ("reserved".to_string(), 2));
+        assert_eq!(plan.spaces[6], ("s1".to_string(), 2));
+        assert_eq!(plan.spaces[10], ("s9".to_string(), 2));
+    }
+
+    #[test]
+    fn swap_moves_either_side() {
+        let mut states = fixture("two_displays");
+        for sides_swapped in [false, true] {
+            if sides_swapped {
+                for space in states.spaces.iter_mut() {
+                    space.label = swap_side(&space.label, space.display);
+                }
+                states.sides_swapped = true;
+            }
+            let moves = swap_moves(&states);
+            assert_eq!(moves.len(), num_spaces() as usize);
+            for (label, display) in moves {
+                let space = states.find_space_by_label(&label).unwrap();
+                assert_ne!(space.display, display, "{} stays put", label);
+            }
+        }
+    }
+
+    #[test]
+    fn composite_action_values() {
+        assert_eq!(
+            composite_action(CompositeOp::Opacity, Some(0.5)).unwrap(),
+            CompositeAction::Opacity(0.5)
+        );
+        assert!(composite_action(CompositeOp::Opacity, None).is_err());
+        assert!(composite_action(CompositeOp::Opacity, Some(1.5)).is_err());
+        assert!(composite_action(CompositeOp::Opacity, Some(-0.1)).is_err());
+        assert!(composite_action(CompositeOp::Float, Some(0.5)).is_err());
+        assert_eq!(
+            composite_action(CompositeOp::Minimize, None).unwrap(),
+            CompositeAction::Minimize
+        );
+    }
+
+    #[test]
+    fn spaces_on_one_display() {
+        let states = fixture("two_displays");
+        let labels: Vec<&str> = spaces_on_display(&states, Some(2))
+            .into_iter()
+            .map(|space| space.label.as_str())
+            .collect();
+        assert_eq!(labels, ["s1", "s3", "s5", "s7", "s9"]);
+        assert_eq!(spaces_on_display(&states, None).len(), states.spaces.len());
+    }
+
+    // Accepts at most a few bytes per write, like a congested socket.
+    struct Trickle {
+        written: Vec<u8>,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_message_handles_partial_writes() {
+        let message = encode_message(&["space", "--focus", "s1"]).unwrap();
+        let mut stream = Trickle {
+            written: Vec::new(),
+        };
+        write_message(&mut stream, &message).unwrap();
+        assert_eq!(stream.written, message);
+    }
+}